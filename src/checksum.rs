@@ -0,0 +1,96 @@
+//! Checksum utilities.
+
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+/// Writer wrapper which calculates SHA-256 checksum and size of the written
+/// data.
+pub struct Sha256Writer<W> {
+    /// Inner writer.
+    inner: W,
+    /// Hasher.
+    hasher: Sha256,
+    /// Number of bytes written.
+    len: u64,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    /// Creates a new `Sha256Writer`.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Feeds the data already written to the inner writer, without writing
+    /// it again.
+    pub fn feed<R: Read>(&mut self, mut reader: R) -> io::Result<()> {
+        let mut buf = [0; 8192];
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.hasher.update(&buf[..len]);
+            self.len += len as u64;
+        }
+    }
+
+    /// Returns the inner writer, the number of bytes written, and the
+    /// hex-encoded checksum.
+    pub fn finish(self) -> (W, u64, String) {
+        (self.inner, self.len, to_hex(&self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encodes the bytes into a lowercase hex string.
+fn to_hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_of_abc() {
+        let mut writer = Sha256Writer::new(Vec::new());
+        writer
+            .feed(&b"a"[..])
+            .expect("reading from a slice should succeed");
+        writer
+            .write_all(b"bc")
+            .expect("writing to a vec should succeed");
+        assert_eq!(
+            writer.finish(),
+            (
+                b"bc".to_vec(),
+                3,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,666 @@
+//! Fankit and image downloader.
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use reqwest::{
+    header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE},
+    Response, StatusCode,
+};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    checksum::Sha256Writer,
+    error::Error,
+    event::{Event, EventSender, SkipReason},
+    fankit::{AssetKind, FankitId, FankitInfo, Site},
+    file::PartFile,
+    filter::FankitFilter,
+    http::{block_on, HttpClient},
+    manifest::{ImageRecord, ItemRecord, Manifest, MANIFEST_FILENAME},
+    naming::{
+        url_filename, NameTemplate, UniqueNames, DEFAULT_DIR_TEMPLATE, DEFAULT_FILE_TEMPLATE,
+    },
+    rate_limit::RequestKind,
+    retry::HttpStatusError,
+    sidecar::{Sidecar, SIDECAR_FILENAME},
+};
+
+/// Default number of parallel downloads.
+const DEFAULT_JOBS: usize = 4;
+
+/// Downloader configuration.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Number of parallel downloads.
+    pub(crate) jobs: usize,
+    /// Filter of fankits to download.
+    pub(crate) filter: Arc<FankitFilter>,
+    /// Sender of the events.
+    pub(crate) events: EventSender,
+    /// Template of the item directories.
+    dir_template: Arc<NameTemplate>,
+    /// Template of the image files.
+    file_template: Arc<NameTemplate>,
+    /// Number of parent path segments of the image URLs kept in the file
+    /// names.
+    parent_segments: usize,
+    /// Kinds of assets to download.
+    asset_kinds: Vec<AssetKind>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            jobs: DEFAULT_JOBS,
+            filter: Default::default(),
+            events: Default::default(),
+            dir_template: Arc::new(
+                NameTemplate::item_dir(DEFAULT_DIR_TEMPLATE).expect("should be valid"),
+            ),
+            file_template: Arc::new(
+                NameTemplate::image_file(DEFAULT_FILE_TEMPLATE).expect("should be valid"),
+            ),
+            parent_segments: 0,
+            asset_kinds: AssetKind::ALL.to_vec(),
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// Sets the number of parallel downloads.
+    ///
+    /// At most `jobs` items are processed and at most `jobs` images are
+    /// downloaded at once.
+    pub fn jobs(self, jobs: usize) -> Self {
+        Self { jobs, ..self }
+    }
+
+    /// Sets the filter of fankits to download.
+    ///
+    /// The ID is checked before the fankit page is loaded, and the others
+    /// are checked before the images are fetched.
+    pub fn filter(self, filter: FankitFilter) -> Self {
+        Self {
+            filter: Arc::new(filter),
+            ..self
+        }
+    }
+
+    /// Sets the sender of the events.
+    pub fn events(self, events: EventSender) -> Self {
+        Self { events, ..self }
+    }
+
+    /// Sets the template of the item directories.
+    ///
+    /// Items already recorded in the manifest are kept in their directories.
+    pub fn dir_template(self, template: NameTemplate) -> Self {
+        Self {
+            dir_template: Arc::new(template),
+            ..self
+        }
+    }
+
+    /// Sets the template of the image files, relative to the item directory.
+    ///
+    /// Images already recorded in the manifest keep their file names.
+    pub fn file_template(self, template: NameTemplate) -> Self {
+        Self {
+            file_template: Arc::new(template),
+            ..self
+        }
+    }
+
+    /// Sets the number of parent path segments of the image URLs kept in the
+    /// file names.
+    ///
+    /// For example, `https://example.com/uploads/pc/01.png` is named
+    /// `pc_01.png` if this is 1, and `01.png` if this is 0 (the default).
+    pub fn parent_segments(self, parent_segments: usize) -> Self {
+        Self {
+            parent_segments,
+            ..self
+        }
+    }
+
+    /// Sets the kinds of assets to download.
+    ///
+    /// All kinds are downloaded by default.
+    pub fn asset_kinds(self, kinds: impl IntoIterator<Item = AssetKind>) -> Self {
+        Self {
+            asset_kinds: kinds.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Returns the record of the item, keeping the directory of the previous
+    /// record if any.
+    pub(crate) fn item_record(
+        &self,
+        info: &FankitInfo,
+        site: &Site,
+        previous: Option<&ItemRecord>,
+    ) -> ItemRecord {
+        match previous {
+            Some(previous) => ItemRecord::renew(info, site, previous),
+            None => ItemRecord::new(info, site, self.dir_template.render(info, site, None)),
+        }
+    }
+
+    /// Returns the URLs of the assets to download in the item with their
+    /// file names relative to the item directory, in the page order.
+    ///
+    /// Images recorded in the previous record keep their file names, and
+    /// images imported without URLs are matched by the file names.
+    /// Colliding names of new images get `-2`, `-3`, ... appended in the
+    /// page order.
+    pub(crate) fn image_filenames<'a>(
+        &self,
+        info: &'a FankitInfo,
+        site: &Site,
+        previous: Option<&ItemRecord>,
+    ) -> Vec<(&'a str, String)> {
+        let assets = info
+            .assets()
+            .iter()
+            .filter(|asset| self.asset_kinds.contains(&asset.kind()));
+
+        let mut names = UniqueNames::default();
+        names.reserve(SIDECAR_FILENAME);
+        let recorded = previous.map_or(&[][..], ItemRecord::images);
+        for image in recorded {
+            names.reserve(image.filename());
+        }
+        // Each imported image is taken by the first new image with its name.
+        let mut imported = recorded
+            .iter()
+            .filter(|image| image.url().is_empty())
+            .map(ImageRecord::filename)
+            .collect::<HashSet<_>>();
+        assets
+            .map(|asset| {
+                let url = asset.url();
+                let filename = match recorded.iter().find(|image| image.url() == url) {
+                    Some(image) => image.filename().to_owned(),
+                    None => {
+                        let filename = self.file_template.render(
+                            info,
+                            site,
+                            Some((asset, &url_filename(url, self.parent_segments))),
+                        );
+                        if imported.remove(filename.as_str()) {
+                            filename
+                        } else {
+                            names.unique(filename)
+                        }
+                    }
+                };
+                (url, filename)
+            })
+            .collect()
+    }
+}
+
+/// Downloads the fankits which are not downloaded yet, blocking the current
+/// thread.
+///
+/// This must not be called from async context.
+pub fn download_fankits(
+    dest_dir: &Path,
+    fankits: &HashSet<FankitId>,
+    manifest: &mut Manifest,
+    client: &HttpClient,
+    site: &Site,
+    config: &DownloadConfig,
+) -> Result<(), Error> {
+    let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+    for &fankit in fankits {
+        fankits_tx
+            .send((site.clone(), fankit))
+            .map_err(Error::internal)?;
+    }
+    drop(fankits_tx);
+
+    block_on(download_fankits_async(
+        dest_dir, fankits_rx, manifest, client, config,
+    ))?
+}
+
+/// Downloads the fankits received from the channel with their sites which
+/// are not downloaded yet, until the channel is closed.
+///
+/// Fankits rejected by the filter in the configuration are skipped, and
+/// recorded in the manifest as filtered.
+/// Fankits whose item pages fail to load or parse are reported by
+/// `ItemFailed` events, without stopping the others.
+/// The manifest is updated and saved each time all images in an item are
+/// done.
+pub async fn download_fankits_async(
+    dest_dir: &Path,
+    mut fankits: mpsc::UnboundedReceiver<(Site, FankitId)>,
+    manifest: &mut Manifest,
+    client: &HttpClient,
+    config: &DownloadConfig,
+) -> Result<(), Error> {
+    let manifest_path = dest_dir.join(MANIFEST_FILENAME);
+    let item_permits = Arc::new(Semaphore::new(config.jobs.max(1)));
+    let image_permits = Arc::new(Semaphore::new(config.jobs.max(1)));
+
+    let mut seen = HashSet::new();
+    let mut items = JoinSet::new();
+    let mut receiving = true;
+    loop {
+        tokio::select! {
+            fankit = fankits.recv(), if receiving => {
+                let (site, fankit) = match fankit {
+                    Some(v) => v,
+                    None => {
+                        receiving = false;
+                        continue;
+                    }
+                };
+                if !seen.insert(fankit) {
+                    continue;
+                }
+                if !config.filter.accepts_id(fankit) {
+                    log::debug!("Fankit {:?} is filtered out", fankit);
+                    config.events.send(|| Event::ItemSkipped {
+                        id: fankit,
+                        reason: SkipReason::Filtered,
+                    });
+                    if manifest.insert_filtered(fankit) {
+                        manifest.save(&manifest_path)?;
+                    }
+                    continue;
+                }
+                let previous = manifest.item(fankit);
+                if previous.is_some_and(|item| item.is_intact(dest_dir)) {
+                    // Already downloaded.
+                    log::info!("Skipping fankit {:?}", fankit);
+                    config.events.send(|| Event::ItemSkipped {
+                        id: fankit,
+                        reason: SkipReason::Downloaded,
+                    });
+                    continue;
+                }
+                items.spawn(download_item(
+                    dest_dir.to_owned(),
+                    site,
+                    fankit,
+                    previous.cloned(),
+                    client.clone(),
+                    config.clone(),
+                    Arc::clone(&item_permits),
+                    Arc::clone(&image_permits),
+                ));
+            }
+            Some(outcome) = items.join_next() => {
+                match outcome.map_err(Error::internal)?? {
+                    ItemOutcome::Done(record) => manifest.insert(record),
+                    ItemOutcome::Filtered(fankit) => {
+                        if !manifest.insert_filtered(fankit) {
+                            continue;
+                        }
+                    }
+                    ItemOutcome::Unavailable => continue,
+                }
+                manifest.save(&manifest_path)?;
+            }
+            else => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Loads the fankit item page.
+///
+/// Returns `None` if the page does not exist, since IDs are sparse and
+/// ranges of IDs are expected to have gaps, or if the page fails to load or
+/// parse.
+/// A failure is reported as an `ItemFailed` event instead of an error, so
+/// that the other items are still downloaded.
+pub(crate) async fn load_item(
+    client: &HttpClient,
+    site: &Site,
+    fankit: FankitId,
+    events: &EventSender,
+) -> Option<FankitInfo> {
+    match fankit.load_async(client, site).await {
+        Ok(info) => Some(info),
+        Err(Error::HttpStatus(e)) if e.status() == StatusCode::NOT_FOUND => {
+            log::warn!("Skipping missing fankit {:?}: {}", fankit, e);
+            events.send(|| Event::ItemSkipped {
+                id: fankit,
+                reason: SkipReason::NotFound,
+            });
+            None
+        }
+        Err(e) => {
+            log::error!("Failed to load fankit {:?}: {}", fankit, e);
+            events.send(|| Event::ItemFailed {
+                id: fankit,
+                error: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// Result of processing a fankit item.
+#[derive(Debug)]
+enum ItemOutcome {
+    /// The images in the item are processed.
+    Done(ItemRecord),
+    /// The item is rejected by the filter.
+    Filtered(FankitId),
+    /// The item page does not exist or fails to load.
+    Unavailable,
+}
+
+/// Downloads the images in the fankit item, and returns the outcome with the
+/// record.
+#[allow(clippy::too_many_arguments)]
+async fn download_item(
+    dest_dir: PathBuf,
+    site: Site,
+    fankit: FankitId,
+    previous: Option<ItemRecord>,
+    client: HttpClient,
+    config: DownloadConfig,
+    item_permits: Arc<Semaphore>,
+    image_permits: Arc<Semaphore>,
+) -> Result<ItemOutcome, Error> {
+    let _permit = item_permits
+        .acquire_owned()
+        .await
+        .map_err(Error::internal)?;
+    let info = match load_item(&client, &site, fankit, &config.events).await {
+        Some(info) => info,
+        None => return Ok(ItemOutcome::Unavailable),
+    };
+
+    log::debug!("info = {:?}", info);
+    let events = &config.events;
+    events.send(|| Event::item_parsed(&site, &info));
+    if !config.filter.accepts(&info) {
+        log::info!("Skipping filtered item {:?}", info.item_name());
+        events.send(|| Event::ItemSkipped {
+            id: fankit,
+            reason: SkipReason::Filtered,
+        });
+        return Ok(ItemOutcome::Filtered(fankit));
+    }
+    log::info!("Downloading images in item {:?}", info.item_name());
+
+    let mut record = config.item_record(&info, &site, previous.as_ref());
+    let item_dir = dest_dir.join(record.dir());
+    fs::create_dir_all(&item_dir).map_err(|e| Error::io(&item_dir, e))?;
+
+    let mut images = Vec::new();
+    let mut downloads = JoinSet::new();
+    let image_filenames = config.image_filenames(&info, &site, previous.as_ref());
+    record.expect_images(image_filenames.len());
+    for (index, (image_url, filename)) in image_filenames.into_iter().enumerate() {
+        let downloaded = previous
+            .as_ref()
+            .and_then(|previous| {
+                previous
+                    .image(image_url)
+                    .or_else(|| previous.imported_image(&filename))
+            })
+            .filter(|image| image.is_intact(&item_dir));
+        if let Some(image) = downloaded {
+            log::trace!("Image {:?} is already downloaded", image_url);
+            images.push(Some(image.clone().with_url(image_url)));
+            continue;
+        }
+        images.push(None);
+
+        let image_path = item_dir.join(&filename);
+        if let Some(parent) = image_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+        let item_dir = item_dir.clone();
+        let image_url = image_url.to_owned();
+        let client = client.clone();
+        let image_permits = Arc::clone(&image_permits);
+        let events = events.clone();
+        downloads.spawn(async move {
+            let _permit = image_permits
+                .acquire_owned()
+                .await
+                .map_err(Error::internal)?;
+            log::trace!("Downloading image {:?}", image_url);
+            events.send(|| Event::ImageStarted {
+                id: fankit,
+                url: image_url.clone(),
+            });
+            let started = Instant::now();
+            let result = download_image_with_retry(&item_dir, &image_url, &filename, &client).await;
+            let duration_ms = started.elapsed().as_millis() as u64;
+            let image = match result {
+                Ok((image, received)) => {
+                    events.send(|| Event::ImageFinished {
+                        id: fankit,
+                        url: image_url.clone(),
+                        filename: filename.clone(),
+                        bytes: received,
+                        duration_ms,
+                    });
+                    image
+                }
+                Err(e) => {
+                    log::error!("Failed to download image {:?}: {}", image_url, e);
+                    events.send(|| Event::ImageFailed {
+                        id: fankit,
+                        url: image_url.clone(),
+                        error: e.to_string(),
+                        duration_ms,
+                    });
+                    ImageRecord::incomplete(&image_url, &filename)
+                }
+            };
+            Ok::<_, Error>((index, image))
+        });
+    }
+    while let Some(result) = downloads.join_next().await {
+        let (index, image) = result.map_err(Error::internal)??;
+        images[index] = Some(image);
+    }
+
+    for image in images.into_iter().flatten() {
+        record.push_image(image);
+    }
+    Sidecar::new(&info, &site, &record).save(&item_dir)?;
+    events.send(|| Event::ItemDone {
+        id: fankit,
+        dir: record.dir().to_owned(),
+        images: record.images().len(),
+        failed: record
+            .images()
+            .iter()
+            .filter(|image| !image.is_complete())
+            .count(),
+    });
+    Ok(ItemOutcome::Done(record))
+}
+
+/// Downloads an image into the item directory, retrying on failures, and
+/// returns the record and the number of bytes received.
+///
+/// Partially downloaded content is kept and resumed on retries and on the
+/// next call.
+/// The bytes received count those of all attempts, but not the content
+/// resumed from the previous calls.
+async fn download_image_with_retry(
+    item_dir: &Path,
+    image_url: &str,
+    image_filename: &str,
+    client: &HttpClient,
+) -> Result<(ImageRecord, u64), Error> {
+    let part = PartFile::new(&item_dir.join(image_filename));
+    let received = AtomicU64::new(0);
+    let image = client
+        .retry()
+        .run(image_url, || {
+            try_download_image(client, &part, image_url, image_filename, &received)
+        })
+        .await?;
+    Ok((image, received.into_inner()))
+}
+
+/// Sends the request for the image, resuming from the given point if
+/// available.
+async fn request_image(
+    client: &HttpClient,
+    image_url: &str,
+    resume_point: Option<&(u64, String)>,
+) -> Result<Response, Error> {
+    let mut req = client.get(image_url, RequestKind::Image).await;
+    if let Some((offset, validator)) = resume_point {
+        log::debug!(
+            "Resuming image {:?} from {} bytes (validator = {:?})",
+            image_url,
+            offset,
+            validator
+        );
+        req = req
+            .header(RANGE, format!("bytes={}-", offset))
+            .header(IF_RANGE, validator.as_str());
+    }
+    client.send(req).await
+}
+
+/// Downloads an image into the part file, and persists it on success.
+///
+/// Returns the record of the image, and adds the bytes received to `received`.
+async fn try_download_image(
+    client: &HttpClient,
+    part: &PartFile,
+    image_url: &str,
+    image_filename: &str,
+    received: &AtomicU64,
+) -> Result<ImageRecord, Error> {
+    let part_error = |e| Error::io(part.part_path(), e);
+    let mut resume_point = part.resume_point().map_err(part_error)?;
+
+    let mut resp = request_image(client, image_url, resume_point.as_ref()).await?;
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE && resume_point.is_some() {
+        log::debug!("Range not satisfiable, downloading {:?} again", image_url);
+        part.discard().map_err(part_error)?;
+        resume_point = None;
+        resp = request_image(client, image_url, None).await?;
+    }
+    let mut resp = HttpStatusError::check(resp)?;
+    let header = |name| {
+        resp.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(ToOwned::to_owned)
+    };
+    let last_modified = header(LAST_MODIFIED);
+    let etag = header(ETAG);
+
+    let offset = match resume_point {
+        Some((offset, _)) if resp.status() == StatusCode::PARTIAL_CONTENT => {
+            if content_range_start(&resp) != Some(offset) {
+                part.discard().map_err(part_error)?;
+                return Err(Error::network(
+                    image_url,
+                    io::Error::new(io::ErrorKind::InvalidData, "Unexpected Content-Range"),
+                ));
+            }
+            offset
+        }
+        Some(_) => {
+            log::debug!(
+                "Server sent the whole content of image {:?}, restarting the download",
+                image_url
+            );
+            0
+        }
+        None => 0,
+    };
+    let expected_len = resp.content_length().map(|len| offset + len);
+
+    let file = part
+        .open(offset, validator(&resp).as_deref())
+        .map_err(part_error)?;
+    let mut writer = Sha256Writer::new(BufWriter::new(file));
+    if offset > 0 {
+        File::open(part.part_path())
+            .and_then(|file| writer.feed(file.take(offset)))
+            .map_err(part_error)?;
+    }
+    while let Some(chunk) = client
+        .timed(image_url, resp.chunk())
+        .await?
+        .map_err(|e| Error::network(image_url, e))?
+    {
+        writer.write_all(&chunk).map_err(part_error)?;
+        received.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    }
+    let (writer, size, sha256) = writer.finish();
+    let file = writer
+        .into_inner()
+        .map_err(|e| part_error(e.into_error()))?;
+    file.sync_all().map_err(part_error)?;
+    drop(file);
+
+    if let Some(expected_len) = expected_len {
+        if size != expected_len {
+            return Err(Error::network(
+                image_url,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Truncated: expected {} bytes, got {} bytes",
+                        expected_len, size
+                    ),
+                ),
+            ));
+        }
+    }
+    part.persist().map_err(part_error)?;
+
+    Ok(
+        ImageRecord::complete(image_url, image_filename, size, sha256)
+            .with_validators(last_modified, etag),
+    )
+}
+
+/// Returns the validator of the response usable for `If-Range` header.
+///
+/// Weak entity tags cannot be used for `If-Range`, so `Last-Modified` is used
+/// in that case.
+fn validator(resp: &Response) -> Option<String> {
+    let headers = resp.headers();
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+        .map(ToOwned::to_owned)
+}
+
+/// Returns the first byte position in the `Content-Range` header.
+fn content_range_start(resp: &Response) -> Option<u64> {
+    let range = resp.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = range.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
@@ -0,0 +1,127 @@
+//! Error type.
+
+use std::{error, fmt, io, path::PathBuf};
+
+use crate::retry::HttpStatusError;
+
+/// Boxed error used as a source of [`Error`].
+type BoxedError = Box<dyn error::Error + Send + Sync + 'static>;
+
+/// Error.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Failed to communicate with the server.
+    Network {
+        /// URL.
+        url: String,
+        /// Underlying error.
+        source: BoxedError,
+    },
+    /// The server responded with an error status.
+    HttpStatus(HttpStatusError),
+    /// An element expected to be in the page is missing, probably because
+    /// the layout of the site has changed.
+    LayoutChanged {
+        /// URL of the page.
+        url: String,
+        /// Selector of the missing element.
+        selector: &'static str,
+    },
+    /// Failed to access the file.
+    Io {
+        /// Path of the file.
+        path: PathBuf,
+        /// Underlying error.
+        source: io::Error,
+    },
+    /// The manifest is broken or unsupported.
+    Manifest {
+        /// Path of the manifest.
+        path: PathBuf,
+        /// Underlying error.
+        source: BoxedError,
+    },
+    /// Invalid configuration.
+    Config(String),
+    /// Unexpected internal failure, such as a panic in a task.
+    Internal(BoxedError),
+}
+
+impl Error {
+    /// Creates a network error.
+    pub(crate) fn network(url: &str, source: impl Into<BoxedError>) -> Self {
+        Self::Network {
+            url: url.to_owned(),
+            source: source.into(),
+        }
+    }
+
+    /// Creates a layout error.
+    pub(crate) fn layout_changed(url: String, selector: &'static str) -> Self {
+        Self::LayoutChanged { url, selector }
+    }
+
+    /// Creates an I/O error.
+    pub(crate) fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Creates a manifest error.
+    pub(crate) fn manifest(path: impl Into<PathBuf>, source: impl Into<BoxedError>) -> Self {
+        Self::Manifest {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+
+    /// Creates an internal error.
+    pub(crate) fn internal(source: impl Into<BoxedError>) -> Self {
+        Self::Internal(source.into())
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Network { source, .. }
+            | Self::Manifest { source, .. }
+            | Self::Internal(source) => Some(&**source),
+            Self::HttpStatus(e) => Some(e),
+            Self::Io { source, .. } => Some(source),
+            Self::LayoutChanged { .. } | Self::Config(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network { url, source } => write!(f, "Failed to fetch {:?}: {}", url, source),
+            Self::HttpStatus(e) => e.fmt(f),
+            Self::LayoutChanged { url, selector } => write!(
+                f,
+                "Element {:?} is not found in {:?}; the site layout may have changed",
+                selector, url
+            ),
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Manifest { path, source } => {
+                write!(f, "Broken manifest {}: {}", path.display(), source)
+            }
+            Self::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            Self::Internal(source) => write!(f, "Internal error: {}", source),
+        }
+    }
+}
+
+impl From<HttpStatusError> for Error {
+    fn from(e: HttpStatusError) -> Self {
+        Self::HttpStatus(e)
+    }
+}
+
+/// Result type with [`Error`].
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,363 @@
+//! Structured events of the crawl and the download.
+//!
+//! Events are sent to an [`EventSender`] as they happen, so that the progress
+//! can be reported in machine-readable form.
+
+use std::{fmt, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{
+    fankit::{Asset, FankitId, FankitInfo, Site},
+    plan::ItemPlan,
+};
+
+/// Event of the crawl and the download.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Event {
+    /// A list page is loaded.
+    ListPage {
+        /// Section of the site.
+        section: String,
+        /// List page index.
+        page: usize,
+        /// URL of the list page.
+        url: String,
+        /// Fankits in the list page.
+        fankits: Vec<FankitId>,
+    },
+    /// A fankit is found in a list page.
+    FankitFound {
+        /// Section of the site.
+        section: String,
+        /// ID.
+        id: FankitId,
+    },
+    /// A fankit item page is parsed.
+    ItemParsed {
+        /// Section of the site.
+        section: String,
+        /// ID.
+        id: FankitId,
+        /// Fankit type.
+        #[serde(rename = "type")]
+        ty: String,
+        /// Title.
+        title: String,
+        /// Item name.
+        item_name: String,
+        /// Image URLs, in the document order.
+        image_urls: Vec<String>,
+        /// Assets of all kinds, in the document order.
+        assets: Vec<Asset>,
+    },
+    /// A fankit is skipped.
+    ItemSkipped {
+        /// ID.
+        id: FankitId,
+        /// Reason.
+        reason: SkipReason,
+    },
+    /// A fankit item page failed to load or parse.
+    ItemFailed {
+        /// ID.
+        id: FankitId,
+        /// Error message.
+        error: String,
+    },
+    /// All images in a fankit item are processed.
+    ItemDone {
+        /// ID.
+        id: FankitId,
+        /// Item directory name, relative to the destination directory.
+        dir: String,
+        /// Number of images in the item.
+        images: usize,
+        /// Number of images failed to download.
+        failed: usize,
+    },
+    /// An image download is started.
+    ImageStarted {
+        /// ID of the fankit.
+        id: FankitId,
+        /// Source URL.
+        url: String,
+    },
+    /// An image is downloaded.
+    ImageFinished {
+        /// ID of the fankit.
+        id: FankitId,
+        /// Source URL.
+        url: String,
+        /// File name in the item directory.
+        filename: String,
+        /// Bytes received in this run, excluding the content resumed from
+        /// a previous run.
+        bytes: u64,
+        /// Duration of the download, including retries, in milliseconds.
+        duration_ms: u64,
+    },
+    /// An image download is failed.
+    ImageFailed {
+        /// ID of the fankit.
+        id: FankitId,
+        /// Source URL.
+        url: String,
+        /// Error message.
+        error: String,
+        /// Duration of the download, including retries, in milliseconds.
+        duration_ms: u64,
+    },
+    /// A fankit would be downloaded (in dry-run mode).
+    ItemPlanned(ItemPlan),
+    /// The whole run is done.
+    Summary(Summary),
+}
+
+impl Event {
+    /// Creates an `ItemParsed` event.
+    pub(crate) fn item_parsed(site: &Site, info: &FankitInfo) -> Self {
+        Self::ItemParsed {
+            section: site.section().to_owned(),
+            id: info.id(),
+            ty: info.ty().to_owned(),
+            title: info.title().to_owned(),
+            item_name: info.item_name(),
+            image_urls: info.image_urls().map(ToOwned::to_owned).collect(),
+            assets: info.assets().to_vec(),
+        }
+    }
+}
+
+/// Reason to skip a fankit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The fankit is already downloaded intact.
+    Downloaded,
+    /// The fankit is rejected by the filter.
+    Filtered,
+    /// The fankit item page does not exist, such as a gap in an ID range.
+    NotFound,
+}
+
+/// Summary of the events.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    /// Number of list pages loaded.
+    list_pages: usize,
+    /// Number of fankits found in the list pages.
+    fankits_found: usize,
+    /// Number of items whose images are all downloaded.
+    items_done: usize,
+    /// Number of items with images failed to download.
+    items_incomplete: usize,
+    /// Number of items whose pages failed to load or parse.
+    items_failed: usize,
+    /// Number of items skipped.
+    items_skipped: usize,
+    /// Number of items planned (in dry-run mode).
+    items_planned: usize,
+    /// Number of images downloaded.
+    images_downloaded: usize,
+    /// Number of images failed to download.
+    images_failed: usize,
+    /// Total bytes of the images received in this run.
+    bytes: u64,
+    /// Elapsed time of the whole run, in milliseconds.
+    elapsed_ms: u64,
+}
+
+impl Summary {
+    /// Updates the summary with the event.
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::ListPage { .. } => self.list_pages += 1,
+            Event::FankitFound { .. } => self.fankits_found += 1,
+            Event::ItemSkipped { .. } => self.items_skipped += 1,
+            Event::ItemFailed { .. } => self.items_failed += 1,
+            Event::ItemDone { failed: 0, .. } => self.items_done += 1,
+            Event::ItemDone { .. } => self.items_incomplete += 1,
+            Event::ImageFinished { bytes, .. } => {
+                self.images_downloaded += 1;
+                self.bytes += bytes;
+            }
+            Event::ImageFailed { .. } => self.images_failed += 1,
+            Event::ItemPlanned(_) => self.items_planned += 1,
+            Event::ItemParsed { .. } | Event::ImageStarted { .. } | Event::Summary(_) => {}
+        }
+    }
+
+    /// Sets the elapsed time of the whole run.
+    pub fn set_elapsed(&mut self, elapsed: Duration) {
+        self.elapsed_ms = elapsed.as_millis() as u64;
+    }
+
+    /// Returns the number of items whose images are all downloaded.
+    pub fn items_done(&self) -> usize {
+        self.items_done
+    }
+
+    /// Returns the number of items with images failed to download.
+    pub fn items_incomplete(&self) -> usize {
+        self.items_incomplete
+    }
+
+    /// Returns the number of items whose pages failed to load or parse.
+    pub fn items_failed(&self) -> usize {
+        self.items_failed
+    }
+
+    /// Returns the number of items skipped.
+    pub fn items_skipped(&self) -> usize {
+        self.items_skipped
+    }
+
+    /// Returns the number of images downloaded.
+    pub fn images_downloaded(&self) -> usize {
+        self.images_downloaded
+    }
+
+    /// Returns the number of images failed to download.
+    pub fn images_failed(&self) -> usize {
+        self.images_failed
+    }
+
+    /// Returns the total bytes of the images received in this run.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns whether nothing has failed.
+    pub fn is_success(&self) -> bool {
+        self.items_incomplete == 0 && self.items_failed == 0 && self.images_failed == 0
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "List pages: {} loaded, {} fankits found",
+            self.list_pages, self.fankits_found
+        )?;
+        writeln!(
+            f,
+            "Items: {} downloaded, {} skipped, {} failed",
+            self.items_done,
+            self.items_skipped,
+            self.items_incomplete + self.items_failed
+        )?;
+        if self.items_planned > 0 {
+            writeln!(f, "Items planned: {}", self.items_planned)?;
+        }
+        writeln!(
+            f,
+            "Images: {} downloaded ({} bytes), {} failed",
+            self.images_downloaded, self.bytes, self.images_failed
+        )?;
+        writeln!(
+            f,
+            "Elapsed: {}.{:03}s",
+            self.elapsed_ms / 1000,
+            self.elapsed_ms % 1000
+        )
+    }
+}
+
+/// Sender of events.
+///
+/// This is cheap to clone. The default sender discards all events.
+#[derive(Debug, Clone, Default)]
+pub struct EventSender(Option<mpsc::UnboundedSender<Event>>);
+
+impl EventSender {
+    /// Creates a new sender and the receiver of the events.
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(Some(tx)), rx)
+    }
+
+    /// Sends the event created by the given function, if anyone receives
+    /// the events.
+    pub fn send(&self, event: impl FnOnce() -> Event) {
+        if let Some(tx) = &self.0 {
+            // Events are optional; it is fine if the receiver is gone.
+            let _ = tx.send(event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let event = Event::ImageFinished {
+            id: FankitId::new(42),
+            url: "https://example.com/a.png".to_owned(),
+            filename: "a.png".to_owned(),
+            bytes: 1234,
+            duration_ms: 56,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"image_finished","id":42,"url":"https://example.com/a.png","filename":"a.png","bytes":1234,"duration_ms":56}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Event::ItemSkipped {
+                id: FankitId::new(42),
+                reason: SkipReason::Filtered,
+            })
+            .unwrap(),
+            r#"{"event":"item_skipped","id":42,"reason":"filtered"}"#
+        );
+    }
+
+    #[test]
+    fn summary() {
+        let mut summary = Summary::default();
+        let events = [
+            Event::ItemDone {
+                id: FankitId::new(1),
+                dir: "1".to_owned(),
+                images: 2,
+                failed: 0,
+            },
+            Event::ItemDone {
+                id: FankitId::new(2),
+                dir: "2".to_owned(),
+                images: 2,
+                failed: 1,
+            },
+            Event::ImageFailed {
+                id: FankitId::new(2),
+                url: "https://example.com/a.png".to_owned(),
+                error: "error".to_owned(),
+                duration_ms: 0,
+            },
+            Event::ItemFailed {
+                id: FankitId::new(3),
+                error: "error".to_owned(),
+            },
+        ];
+        for event in &events {
+            summary.record(event);
+        }
+        assert_eq!(
+            summary,
+            Summary {
+                items_done: 1,
+                items_incomplete: 1,
+                items_failed: 1,
+                images_failed: 1,
+                ..Summary::default()
+            }
+        );
+        assert!(!summary.is_success());
+    }
+}
@@ -1,71 +1,239 @@
 //! Fankit-related stuff.
 
-use std::{
-    collections::{HashSet, VecDeque},
-    time::Duration,
+use std::collections::{BTreeSet, HashSet};
+
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::{
+    error::Error,
+    event::{Event, EventSender},
+    http::{block_on, HttpClient},
 };
 
-pub use self::{id::FankitId, info::FankitInfo, list_page_index::FankitListPageIndex};
+pub use self::{
+    asset::{Asset, AssetKind},
+    id::FankitId,
+    info::FankitInfo,
+    list_page_index::FankitListPageIndex,
+    site::{Site, DEFAULT_BASE_URL, DEFAULT_SECTION},
+    target::{parse_id_range, FankitTarget, FankitTargetParseError, MAX_TARGET_IDS},
+};
 
+mod asset;
 mod id;
 mod info;
 mod list_page_index;
+mod site;
+mod target;
+
+/// Returns fankits if new fankit is detected, blocking the current thread.
+///
+/// See [`crawl_fankits_if_new_fankit_found`] for `known_pages_to_stop`.
+///
+/// This must not be called from async context.
+pub fn get_fankits_if_new_fankit_found(
+    known_fankits: impl IntoIterator<Item = FankitId>,
+    client: &HttpClient,
+    site: &Site,
+    known_pages_to_stop: usize,
+) -> Result<Option<HashSet<FankitId>>, Error> {
+    let known_fankits = known_fankits.into_iter().collect::<HashSet<_>>();
+    block_on(async {
+        let (found_tx, mut found_rx) = mpsc::unbounded_channel();
+        if !crawl_fankits_if_new_fankit_found(
+            &known_fankits,
+            client,
+            site,
+            known_pages_to_stop,
+            &found_tx,
+            &EventSender::default(),
+        )
+        .await?
+        {
+            return Ok(None);
+        }
+        drop(found_tx);
 
-/// Common URL prefix for fankit-related pages.
-const URL_FANKIT_TOP: &str = "https://priconne-redive.jp/fankit02/";
-
-/// URL prefix for fankit items.
-const URL_FANKIT_ITEM_BASE: &str = URL_FANKIT_TOP;
-
-/// URL prefix for fankit list pages.
-const URL_FANKIT_LIST_BASE: &str = "https://priconne-redive.jp/fankit02/page/";
+        let mut fankits = HashSet::new();
+        while let Some((_, fankit)) = found_rx.recv().await {
+            fankits.insert(fankit);
+        }
+        Ok(Some(fankits))
+    })?
+}
 
-/// Returns fankits if new fankit is detected.
-pub fn get_fankits_if_new_fankit_found(
+/// Returns the fankits in the site which are not known, walking all the list
+/// pages, and blocking the current thread.
+///
+/// This must not be called from async context.
+pub fn get_missing_fankits(
     known_fankits: impl IntoIterator<Item = FankitId>,
-    crawl_delay: Duration,
-) -> Result<Option<HashSet<FankitId>>, Box<dyn std::error::Error + Send + Sync + 'static>> {
-    use std::iter::FromIterator;
+    client: &HttpClient,
+    site: &Site,
+) -> Result<HashSet<FankitId>, Error> {
+    let known_fankits = known_fankits.into_iter().collect::<HashSet<_>>();
+    block_on(async {
+        let (found_tx, _found_rx) = mpsc::unbounded_channel();
+        let missing = crawl_missing_fankits(
+            &known_fankits,
+            client,
+            site,
+            &found_tx,
+            &EventSender::default(),
+        )
+        .await?;
+        Ok(missing.into_iter().collect())
+    })?
+}
 
-    const FIRST_PAGE_INDEX: FankitListPageIndex = FankitListPageIndex::new(1);
+/// Sends fankits in the site to `found` as soon as they are found, if new
+/// fankit is detected.
+///
+/// The list pages are walked from newer to older, and the walk stops once
+/// `known_pages_to_stop` consecutive list pages have only known fankits.
+/// This means only the first list page is loaded if it has no new fankits.
+///
+/// Returns `false` if no new fankits are detected.
+pub async fn crawl_fankits_if_new_fankit_found(
+    known_fankits: &HashSet<FankitId>,
+    client: &HttpClient,
+    site: &Site,
+    known_pages_to_stop: usize,
+    found: &UnboundedSender<(Site, FankitId)>,
+    events: &EventSender,
+) -> Result<bool, Error> {
+    let known_pages_to_stop = known_pages_to_stop.max(1);
+    let mut list_pages = ListPages::new();
+    let mut new_found = false;
+    let mut known_pages = 0;
+
+    while let Some(fankits) = list_pages.next(client, site, events).await? {
+        if fankits.iter().all(|fankit| known_fankits.contains(fankit)) {
+            known_pages += 1;
+        } else {
+            new_found = true;
+            known_pages = 0;
+        }
+        if !new_found {
+            // There are no new fankits.
+            return Ok(false);
+        }
+        send_all(found, site, fankits, events)?;
+        if known_pages >= known_pages_to_stop {
+            log::debug!(
+                "Stopping the crawl after {} list pages with only known fankits",
+                known_pages
+            );
+            break;
+        }
+    }
 
-    let (new_fankits, other_lists) = FIRST_PAGE_INDEX.load()?;
-    let new_fankits = HashSet::from_iter(new_fankits);
-    let known_fankits = HashSet::from_iter(known_fankits);
+    Ok(new_found)
+}
 
-    if new_fankits.is_subset(&known_fankits) {
-        // There are no new fankits.
-        return Ok(None);
+/// Walks all the list pages in the site, and sends fankits which are not
+/// known to `found` as soon as they are found.
+///
+/// Unlike [`crawl_fankits_if_new_fankit_found`], this does not stop even if
+/// the first list page has no new fankits, so that the fankits missing from
+/// the older list pages are found.
+///
+/// Returns the fankits sent, in the order they are found.
+pub async fn crawl_missing_fankits(
+    known_fankits: &HashSet<FankitId>,
+    client: &HttpClient,
+    site: &Site,
+    found: &UnboundedSender<(Site, FankitId)>,
+    events: &EventSender,
+) -> Result<Vec<FankitId>, Error> {
+    let mut list_pages = ListPages::new();
+    let mut seen = HashSet::new();
+    let mut missing = Vec::new();
+
+    while let Some(fankits) = list_pages.next(client, site, events).await? {
+        let fankits = fankits
+            .into_iter()
+            .filter(|fankit| !known_fankits.contains(fankit) && seen.insert(*fankit))
+            .collect::<Vec<_>>();
+        missing.extend_from_slice(&fankits);
+        send_all(found, site, fankits, events)?;
     }
 
-    // Wanted to pop from `HashSet` but it is not in std hashset.
-    // Using `VecDeque` instead.
-    let mut list_undone = VecDeque::from_iter(other_lists);
+    Ok(missing)
+}
 
-    let mut fankits = new_fankits;
-    let mut list_done = std::iter::once(FIRST_PAGE_INDEX).collect::<HashSet<_>>();
+/// Walker of the list pages in a site, visiting each list page once in the
+/// order of the index.
+#[derive(Debug)]
+struct ListPages {
+    /// List pages found but not visited yet.
+    undone: BTreeSet<FankitListPageIndex>,
+    /// List pages visited or queued.
+    done: HashSet<FankitListPageIndex>,
+}
 
-    // Load the index pages.
-    while let Some(list_page) = list_undone.pop_front() {
-        if !list_done.insert(list_page) {
-            // `list_page` is already checked.
-            continue;
+impl ListPages {
+    /// Creates a new walker starting from the first list page.
+    fn new() -> Self {
+        let first = FankitListPageIndex::new(1);
+        Self {
+            undone: std::iter::once(first).collect(),
+            done: std::iter::once(first).collect(),
         }
+    }
 
-        let (new_fankits, other_lists) = list_page.load()?;
-        list_undone.extend(other_lists.into_iter().filter(|v| !list_done.contains(v)));
-
-        fankits.extend(new_fankits);
+    /// Loads the next list page, and returns the fankits in it.
+    ///
+    /// Returns `None` if all list pages are visited.
+    async fn next(
+        &mut self,
+        client: &HttpClient,
+        site: &Site,
+        events: &EventSender,
+    ) -> Result<Option<Vec<FankitId>>, Error> {
+        let list_page = match self.undone.iter().next() {
+            Some(&v) => v,
+            None => return Ok(None),
+        };
+        self.undone.remove(&list_page);
+
+        let (fankits, other_lists) = list_page.load_async(client, site).await?;
+        events.send(|| Event::ListPage {
+            section: site.section().to_owned(),
+            page: list_page.to_usize(),
+            url: list_page.to_url(site),
+            fankits: fankits.clone(),
+        });
+        for other in other_lists {
+            if self.done.insert(other) {
+                self.undone.insert(other);
+            }
+        }
 
         log::debug!(
             "List pages done = {:?}, undone = {:?}",
-            list_done,
-            list_undone
+            self.done,
+            self.undone
         );
-
-        log::debug!("Sleeping for {:?}", crawl_delay);
-        std::thread::sleep(crawl_delay);
+        Ok(Some(fankits))
     }
+}
 
-    Ok(Some(fankits))
+/// Sends the fankits in the site to the channel.
+fn send_all(
+    found: &UnboundedSender<(Site, FankitId)>,
+    site: &Site,
+    fankits: impl IntoIterator<Item = FankitId>,
+    events: &EventSender,
+) -> Result<(), Error> {
+    for fankit in fankits {
+        events.send(|| Event::FankitFound {
+            section: site.section().to_owned(),
+            id: fankit,
+        });
+        found
+            .send((site.clone(), fankit))
+            .map_err(|_| Error::internal("The receiver of fankits is closed"))?;
+    }
+    Ok(())
 }
@@ -0,0 +1,375 @@
+//! Assets linked from fankit item pages.
+
+use std::{collections::HashSet, fmt, rc::Rc, str::FromStr};
+
+use markup5ever_rcdom::Handle;
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+    error::Error,
+    node::{inner_text, node_attr, node_is_element, parent, resolve_url, Traverse},
+};
+
+/// Maximum length of a label in characters.
+///
+/// Longer texts are not considered as labels.
+const MAX_LABEL_CHARS: usize = 64;
+
+/// Maximum number of ancestors of a link searched for the label.
+const MAX_LABEL_DEPTH: usize = 3;
+
+/// Kind of an asset, determined by the extension of the URL path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetKind {
+    /// Image (`.jpg`, `.jpeg`, `.png`, `.gif`, and `.webp`).
+    Image,
+    /// Archive (`.zip`).
+    Archive,
+    /// Document (`.pdf`).
+    Document,
+}
+
+impl AssetKind {
+    /// All asset kinds.
+    pub const ALL: [Self; 3] = [Self::Image, Self::Archive, Self::Document];
+
+    /// Returns the kind of the asset at the URL, if the URL is an asset.
+    ///
+    /// The extension is compared case-insensitively.
+    pub fn from_url(url: &Url) -> Option<Self> {
+        let filename = url.path().rsplit('/').next().unwrap_or_default();
+        let (_, ext) = filename.rsplit_once('.')?;
+        match ext.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" => Some(Self::Image),
+            "zip" => Some(Self::Archive),
+            "pdf" => Some(Self::Document),
+            _ => None,
+        }
+    }
+
+    /// Returns the name of the kind.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Image => "image",
+            Self::Archive => "archive",
+            Self::Document => "document",
+        }
+    }
+}
+
+impl FromStr for AssetKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.as_str() == s.trim())
+            .ok_or_else(|| Error::Config(format!("Unknown asset kind {:?}", s)))
+    }
+}
+
+impl fmt::Display for AssetKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Asset linked from a fankit item page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Asset {
+    /// URL.
+    url: String,
+    /// Kind.
+    kind: AssetKind,
+    /// Position in the page, starting from 1.
+    position: usize,
+    /// Label text near the link, such as `PC` and `iPhone`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+}
+
+impl Asset {
+    /// Returns the URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the kind.
+    pub fn kind(&self) -> AssetKind {
+        self.kind
+    }
+
+    /// Returns the position in the page, starting from 1.
+    ///
+    /// Assets are numbered in the document order, regardless of the kinds.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the label text near the link, such as `PC` and `iPhone`, if
+    /// found.
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// Returns the assets referenced in the node, in the document order without
+/// duplicates.
+///
+/// Assets are collected from `href` of anchors, and `src`, `srcset`,
+/// `data-src`, and `data-srcset` of `<img>` and `<source>` elements.
+/// URLs are resolved against the base URL, and URLs without known asset
+/// extensions are ignored.
+///
+/// Each `<img>` and `<source>` element yields at most one asset, preferring
+/// the largest `srcset` candidate and the lazy-loaded `data-*` attributes,
+/// and yields none inside an anchor which already yielded an asset, since
+/// such images are thumbnails of the linked assets.
+pub(crate) fn extract_assets(root: Handle, base: &Url) -> Vec<Asset> {
+    let mut seen = HashSet::new();
+    let mut assets = Vec::new();
+    let mut asset_anchors = Vec::new();
+    for node in Traverse::new(root.clone()) {
+        let mut links = Vec::new();
+        if node_is_element("a", &node) {
+            links.extend(node_attr("href", &node));
+        } else if node_is_element("img", &node) || node_is_element("source", &node) {
+            if has_ancestor_in(&node, &asset_anchors, &root) {
+                continue;
+            }
+            for name in &["data-srcset", "srcset"] {
+                if let Some(srcset) = node_attr(name, &node) {
+                    links.extend(largest_srcset_url(&srcset).map(ToOwned::to_owned));
+                }
+            }
+            links.extend(node_attr("data-src", &node));
+            links.extend(node_attr("src", &node));
+        }
+        let link = links
+            .iter()
+            .filter_map(|link| resolve_url(base, link))
+            .find_map(|url| AssetKind::from_url(&url).map(|kind| (url, kind)));
+        let (url, kind) = match link {
+            Some(link) => link,
+            None => continue,
+        };
+        if node_is_element("a", &node) {
+            asset_anchors.push(node.clone());
+        }
+        let url = String::from(url);
+        if seen.insert(url.clone()) {
+            assets.push(Asset {
+                url,
+                kind,
+                position: assets.len() + 1,
+                label: label(&node, &root),
+            });
+        }
+    }
+    assets
+}
+
+/// Returns whether any of the ancestors of the node under the root is one of
+/// the given elements.
+fn has_ancestor_in(node: &Handle, elements: &[Handle], root: &Handle) -> bool {
+    let mut ancestor = node.clone();
+    while let Some(parent) = parent(&ancestor) {
+        if elements.iter().any(|element| Rc::ptr_eq(element, &parent)) {
+            return true;
+        }
+        if Rc::ptr_eq(&parent, root) {
+            break;
+        }
+        ancestor = parent;
+    }
+    false
+}
+
+/// Returns the label text of the link element under the root.
+///
+/// The label is the text of the element itself (such as the text of an
+/// anchor), the `alt` or `title` attribute of the element or its
+/// descendants, or the text of the nearest ancestor with a text (such as a
+/// list item or a figure) not shared with other links, whichever is found
+/// first.
+fn label(node: &Handle, root: &Handle) -> Option<String> {
+    let short = |text: String| {
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        Some(text).filter(|text| !text.is_empty() && text.chars().count() <= MAX_LABEL_CHARS)
+    };
+
+    let text = short(inner_text(node.clone()));
+    if text.is_some() {
+        return text;
+    }
+    let alt = Traverse::new(node.clone()).find_map(|node| {
+        ["alt", "title"]
+            .iter()
+            .find_map(|name| node_attr(name, &node).and_then(short))
+    });
+    if alt.is_some() {
+        return alt;
+    }
+
+    let mut ancestor = node.clone();
+    for _ in 0..MAX_LABEL_DEPTH {
+        ancestor = parent(&ancestor)?;
+        if Rc::ptr_eq(&ancestor, root) || count_links(&ancestor) > count_links(node) {
+            // The text would be shared with other links.
+            break;
+        }
+        let text = inner_text(ancestor.clone());
+        if !text.trim().is_empty() {
+            // Too long texts are descriptions of something else.
+            return short(text);
+        }
+    }
+    None
+}
+
+/// Returns the number of elements which may link to assets in the node.
+fn count_links(node: &Handle) -> usize {
+    Traverse::new(node.clone())
+        .filter(|node| {
+            ["a", "img", "source"]
+                .iter()
+                .any(|name| node_is_element(name, node))
+        })
+        .count()
+}
+
+/// Returns the URL of the largest candidate in the `srcset` attribute.
+///
+/// Candidates are compared by the width (`w`) or the pixel density (`x`)
+/// descriptors, and a candidate without descriptors is `1x`.
+/// The first one is returned if the largest candidates are tied.
+fn largest_srcset_url(srcset: &str) -> Option<&str> {
+    srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = parts.next()?;
+            let size = parts
+                .next()
+                .and_then(|descriptor| {
+                    descriptor
+                        .strip_suffix('w')
+                        .or_else(|| descriptor.strip_suffix('x'))
+                })
+                .and_then(|size| size.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            Some((url, size))
+        })
+        .fold(
+            None,
+            |largest: Option<(&str, f64)>, (url, size)| match largest {
+                Some((_, largest_size)) if largest_size >= size => largest,
+                _ => Some((url, size)),
+            },
+        )
+        .map(|(url, _)| url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::node::parse_dom;
+
+    #[test]
+    fn kinds() {
+        let kind = |url| AssetKind::from_url(&Url::parse(url).unwrap());
+        assert_eq!(kind("https://example.com/a.JPEG"), Some(AssetKind::Image));
+        assert_eq!(
+            kind("https://example.com/a.webp?v=1"),
+            Some(AssetKind::Image)
+        );
+        assert_eq!(kind("https://example.com/a.zip"), Some(AssetKind::Archive));
+        assert_eq!(kind("https://example.com/a.pdf"), Some(AssetKind::Document));
+        assert_eq!(kind("https://example.com/a.html"), None);
+        assert_eq!(kind("https://example.com/a.png/"), None);
+        assert_eq!(kind("https://example.com/png"), None);
+
+        assert_eq!(
+            "archive".parse::<AssetKind>().ok(),
+            Some(AssetKind::Archive)
+        );
+        assert!(matches!(
+            "video".parse::<AssetKind>(),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn positions_and_labels() {
+        let html = r#"<html><body><div id="contents">
+            <p>Long description of the fankit, which is not a label of any images.</p>
+            <ul>
+                <li><span>PC</span><a href="pc.png"><img src="pc.png" alt=""></a></li>
+                <li><a href="sp.png"><img src="sp.png" alt="iPhone"></a></li>
+                <li><a href="tablet.png">Tablet</a></li>
+            </ul>
+            <figure><img src="fig.png"><figcaption>Android</figcaption></figure>
+            <a href="set.zip"><img src="zip.gif"></a>
+        </div></body></html>"#;
+        let document = parse_dom(html).document;
+        // Keep `document` alive, since dropping it drops the whole tree.
+        let root = Traverse::new(document.clone())
+            .find(|node| crate::node::node_has_id("contents", node))
+            .unwrap();
+        let base = Url::parse("https://example.com/").unwrap();
+        let assets = extract_assets(root, &base);
+        let assets = assets
+            .iter()
+            .map(|asset| (asset.position(), asset.label(), asset.url()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            assets,
+            vec![
+                (1, Some("PC"), "https://example.com/pc.png"),
+                (2, Some("iPhone"), "https://example.com/sp.png"),
+                (3, Some("Tablet"), "https://example.com/tablet.png"),
+                (4, Some("Android"), "https://example.com/fig.png"),
+                (5, None, "https://example.com/set.zip"),
+            ]
+        );
+    }
+
+    #[test]
+    fn extract() {
+        let html = r#"<html><body>
+            <a href="a.PNG">a</a>
+            <a href="b.zip"><img src="b_thumb.jpg" alt=""></a>
+            <img data-src="c.webp" src="data:image/gif;base64,R0lGODlhAQABAAAAACw=">
+            <picture>
+                <source srcset="d.webp 1x, d@2x.webp 2x">
+                <img src="e-300.jpeg" srcset="e-600.jpeg 600w,e-300.jpeg 300w">
+            </picture>
+            <a href="f.pdf">f</a>
+            <a href="a.PNG#dup">a</a>
+            <a href="index.html"><img src="g.png" alt=""></a>
+        </body></html>"#;
+        let base = Url::parse("https://example.com/").unwrap();
+        let assets = extract_assets(parse_dom(html).document, &base);
+        let assets = assets
+            .iter()
+            .map(|asset| (asset.url(), asset.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            assets,
+            vec![
+                ("https://example.com/a.PNG", AssetKind::Image),
+                ("https://example.com/b.zip", AssetKind::Archive),
+                ("https://example.com/c.webp", AssetKind::Image),
+                ("https://example.com/d@2x.webp", AssetKind::Image),
+                ("https://example.com/e-600.jpeg", AssetKind::Image),
+                ("https://example.com/f.pdf", AssetKind::Document),
+                // Images in anchors to non-assets are not thumbnails.
+                ("https://example.com/g.png", AssetKind::Image),
+            ]
+        );
+    }
+}
@@ -2,24 +2,52 @@
 
 use std::{error, fmt};
 
+use serde::{Deserialize, Serialize};
+use url::Url;
+
 use crate::{
-    fankit::{FankitInfo, URL_FANKIT_ITEM_BASE},
-    node::load_dom,
+    error::Error,
+    fankit::{FankitInfo, Site},
+    http::{block_on, HttpClient},
+    node::load_dom_async,
 };
 
 /// Fankit ID.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct FankitId(usize);
 
 impl FankitId {
     /// Creates a new `FankitId`.
-    pub(crate) fn new(v: usize) -> Self {
+    pub fn new(v: usize) -> Self {
         Self(v)
     }
 
-    /// Returns the URL of the fankit.
-    pub fn to_url(self) -> String {
-        format!("{}{}/", URL_FANKIT_ITEM_BASE, self.0)
+    /// Returns the URL of the fankit in the site.
+    pub fn to_url(self, site: &Site) -> String {
+        format!("{}{}/", site.item_base(), self.0)
+    }
+
+    /// Parses the URL of the fankit in the site.
+    ///
+    /// Equivalent forms of the URL are accepted: `http` and `https` are not
+    /// distinguished, and the trailing slash, the query, and the fragment are
+    /// optional.
+    pub fn from_url(url: &str, site: &Site) -> Result<Self, FankitIdParseError> {
+        let url = Url::parse(url.trim()).map_err(|_| FankitIdParseError::InvalidUrl)?;
+        Self::from_parsed_url(&url, site)
+    }
+
+    /// Parses the URL of the fankit in the site.
+    pub(crate) fn from_parsed_url(url: &Url, site: &Site) -> Result<Self, FankitIdParseError> {
+        let relpath = site
+            .relative_path(url)
+            .ok_or(FankitIdParseError::BaseMismatch)?
+            .trim_end_matches('/');
+        relpath
+            .parse::<usize>()
+            .map(Self::new)
+            .map_err(|_| FankitIdParseError::InvalidPath)
     }
 
     /// Returns `usize` value.
@@ -27,32 +55,28 @@ impl FankitId {
         self.0
     }
 
-    /// Loads the fankit page, and returns a metadata and the image URLs.
-    pub fn load(self) -> Result<FankitInfo, Box<dyn error::Error + Send + Sync + 'static>> {
-        log::trace!("Loading fankit page: {:?}", self);
-        let dom = load_dom(&self.to_url())?;
-        FankitInfo::from_node(self, dom.document)
+    /// Loads the fankit page, and returns a metadata and the image URLs,
+    /// blocking the current thread.
+    ///
+    /// This must not be called from async context.
+    pub fn load(self, client: &HttpClient, site: &Site) -> Result<FankitInfo, Error> {
+        block_on(self.load_async(client, site))?
     }
-}
 
-impl std::str::FromStr for FankitId {
-    type Err = FankitIdParseError;
-
-    fn from_str(url: &str) -> Result<Self, Self::Err> {
-        if !url.starts_with(URL_FANKIT_ITEM_BASE) {
-            return Err(FankitIdParseError::BaseMismatch);
-        }
-        let relpath = url[URL_FANKIT_ITEM_BASE.len()..].trim_end_matches('/');
-        relpath
-            .parse::<usize>()
-            .map(Self::new)
-            .map_err(|_| FankitIdParseError::InvalidPath)
+    /// Loads the fankit page, and returns a metadata and the image URLs.
+    pub async fn load_async(self, client: &HttpClient, site: &Site) -> Result<FankitInfo, Error> {
+        log::trace!("Loading fankit page: {:?}", self);
+        let url = self.to_url(site);
+        let dom = load_dom_async(&url, client).await?;
+        FankitInfo::from_node(self, &url, dom.document)
     }
 }
 
 /// `FankitListPageIndex` parse error.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FankitIdParseError {
+    /// Invalid URL.
+    InvalidUrl,
     /// Base URL mismatch.
     BaseMismatch,
     /// Invalid path.
@@ -64,6 +88,7 @@ impl error::Error for FankitIdParseError {}
 impl fmt::Display for FankitIdParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::InvalidUrl => f.write_str("Invalid URL"),
             Self::BaseMismatch => f.write_str("Base URL mismatch"),
             Self::InvalidPath => f.write_str("Invalid path"),
         }
@@ -77,22 +102,54 @@ mod tests {
     #[test]
     fn to_url() {
         assert_eq!(
-            FankitId::new(1234).to_url(),
+            FankitId::new(1234).to_url(&Site::default()),
             "https://priconne-redive.jp/fankit02/1234/"
         );
     }
 
     #[test]
     fn list_index() {
+        let site = Site::default();
         assert_eq!(
-            "https://priconne-redive.jp/fankit02/1234/"
-                .parse::<FankitId>()
-                .ok(),
+            FankitId::from_url("https://priconne-redive.jp/fankit02/1234/", &site).ok(),
+            Some(FankitId::new(1234))
+        );
+        assert!(FankitId::from_url("https://priconne-redive.jp/fankit02/page/4/", &site).is_err());
+        assert!(FankitId::from_url("https://priconne-redive.jp/", &site).is_err());
+        assert_eq!(
+            FankitId::from_url(
+                "http://127.0.0.1:8080/fankit02/1234/",
+                &Site::new("http://127.0.0.1:8080/", "fankit02").unwrap()
+            )
+            .ok(),
             Some(FankitId::new(1234))
         );
-        assert!("https://priconne-redive.jp/fankit02/page/4/"
-            .parse::<FankitId>()
-            .is_err());
-        assert!("https://priconne-redive.jp/".parse::<FankitId>().is_err());
+    }
+
+    #[test]
+    fn equivalent_urls() {
+        let site = Site::default();
+        for url in &[
+            "https://priconne-redive.jp/fankit02/1234",
+            "http://priconne-redive.jp/fankit02/1234/",
+            "https://PRICONNE-REDIVE.JP/fankit02/1234/?utm_source=twitter",
+            "https://priconne-redive.jp:443/fankit02/1234/#images",
+            " https://priconne-redive.jp/fankit02/./1234/ ",
+        ] {
+            assert_eq!(
+                FankitId::from_url(url, &site),
+                Ok(FankitId::new(1234)),
+                "url = {:?}",
+                url
+            );
+        }
+        assert_eq!(
+            FankitId::from_url("/fankit02/1234/", &site),
+            Err(FankitIdParseError::InvalidUrl)
+        );
+        assert_eq!(
+            FankitId::from_url("https://example.com/fankit02/1234/", &site),
+            Err(FankitIdParseError::BaseMismatch)
+        );
     }
 }
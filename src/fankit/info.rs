@@ -1,12 +1,18 @@
 //! Fankit info.
 
-use std::collections::HashSet;
-
 use markup5ever_rcdom::Handle;
+use url::Url;
 
 use crate::{
-    fankit::FankitId,
-    node::{get_anchors, inner_text, node_has_class, node_has_id, Traverse},
+    error::Error,
+    fankit::{
+        asset::{extract_assets, Asset, AssetKind},
+        FankitId, Site,
+    },
+    node::{
+        document_base, inner_text, node_attr, node_has_class, node_has_id, node_is_element,
+        parse_dom, Traverse,
+    },
 };
 
 /// Fankit info.
@@ -18,25 +24,72 @@ pub struct FankitInfo {
     ty: String,
     /// Title.
     title: String,
-    /// Image URLs.
-    image_urls: HashSet<String>,
+    /// Description, if available.
+    description: Option<String>,
+    /// Publication date, if available.
+    published: Option<String>,
+    /// Assets in the document order.
+    assets: Vec<Asset>,
 }
 
 impl FankitInfo {
+    /// Returns the ID.
+    pub fn id(&self) -> FankitId {
+        self.id
+    }
+
+    /// Returns the fankit type.
+    pub fn ty(&self) -> &str {
+        &self.ty
+    }
+
+    /// Returns the title.
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Returns the description, if the page has one.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Returns the publication date, if the page has one.
+    ///
+    /// This is the `datetime` attribute of the `<time>` element if available,
+    /// or the text of the date element as is.
+    pub fn published(&self) -> Option<&str> {
+        self.published.as_deref()
+    }
+
     /// Returns the item name.
     pub fn item_name(&self) -> String {
         format!("{}-{}-{}", self.id.to_usize(), self.ty, self.title)
     }
 
-    /// Returns an iterator of image URLs.
+    /// Returns the assets in the document order.
+    pub fn assets(&self) -> &[Asset] {
+        &self.assets
+    }
+
+    /// Returns an iterator of image URLs in the document order.
     pub fn image_urls(&self) -> impl Iterator<Item = &str> {
-        self.image_urls.iter().map(String::as_str)
+        self.assets
+            .iter()
+            .filter(|asset| asset.kind() == AssetKind::Image)
+            .map(Asset::url)
+    }
+
+    /// Parses the HTML of the fankit item page in the site.
+    pub fn parse_html(id: FankitId, site: &Site, html: &str) -> Result<Self, Error> {
+        Self::from_node(id, &id.to_url(site), parse_dom(html).document)
     }
 
-    pub(crate) fn from_node(
-        id: FankitId,
-        node: Handle,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// Parses the document of the fankit item page at the URL.
+    pub(crate) fn from_node(id: FankitId, url: &str, node: Handle) -> Result<Self, Error> {
+        // The URL is built from the site, whose base URL is already validated.
+        let page_url = Url::parse(url).map_err(Error::internal)?;
+        let base = document_base(&node, &page_url);
+
         // Node with ID value `contents`.
         //
         // This `.clone()` for `node` should NOT be removed, because the `node` has all document
@@ -45,11 +98,11 @@ impl FankitInfo {
         #[allow(clippy::redundant_clone)]
         let contents_elem = Traverse::new(node.clone())
             .find(|node| node_has_id("contents", node))
-            .ok_or("Failed to get contents element")?;
+            .ok_or_else(|| Error::layout_changed(url.to_owned(), "#contents"))?;
 
         let fankit_type_elem = Traverse::new(contents_elem.clone())
             .find(|node| node_has_class("fankit-type", node))
-            .ok_or("Failed to get fankit type")?;
+            .ok_or_else(|| Error::layout_changed(url.to_owned(), "#contents .fankit-type"))?;
         let ty = inner_text(fankit_type_elem)
             .replace(char::is_whitespace, " ")
             .trim()
@@ -57,22 +110,147 @@ impl FankitInfo {
 
         let fankit_title_elem = Traverse::new(contents_elem.clone())
             .find(|node| node_has_class("title", node))
-            .ok_or("Failed to get fankit title")?;
+            .ok_or_else(|| Error::layout_changed(url.to_owned(), "#contents .title"))?;
         let title = inner_text(fankit_title_elem)
             .replace(char::is_whitespace, " ")
             .trim()
             .to_owned();
 
-        let image_urls = get_anchors(contents_elem)
-            .filter(|url| url.ends_with(".jpg") || url.ends_with(".png"))
-            .map(|url| url.trim().to_owned())
-            .collect();
+        // Optional elements.
+        let description = Traverse::new(contents_elem.clone())
+            .find(|node| node_has_class("description", node))
+            .map(|node| {
+                inner_text(node)
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .filter(|description| !description.is_empty());
+        let published = Traverse::new(contents_elem.clone())
+            .find(|node| node_is_element("time", node) || node_has_class("date", node))
+            .map(|node| {
+                node_attr("datetime", &node)
+                    .unwrap_or_else(|| inner_text(node))
+                    .trim()
+                    .to_owned()
+            })
+            .filter(|published| !published.is_empty());
+
+        let assets = extract_assets(contents_elem, &base);
 
         Ok(Self {
             id,
             ty,
             title,
-            image_urls,
+            description,
+            published,
+            assets,
         })
     }
 }
+
+#[cfg(test)]
+impl FankitInfo {
+    /// Returns the info of an item page with the type and the title, and no
+    /// assets, for the tests.
+    pub(crate) fn fixture(id: usize, ty: &str, title: &str) -> Self {
+        let html = format!(
+            r#"<html><body><div id="contents">
+            <p class="fankit-type">{}</p>
+            <h2 class="title">{}</h2>
+            </div></body></html>"#,
+            ty, title
+        );
+        Self::parse_html(FankitId::new(id), &Site::default(), &html).expect("should be parsable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_html() {
+        let html = r#"<html><body><div id="contents">
+            <p class="fankit-type">Wallpaper</p>
+            <h2 class="title"> Title </h2>
+            <a href="https://example.com/a.png">a</a>
+            <a href="https://example.com/">top</a>
+        </div></body></html>"#;
+        let info = FankitInfo::parse_html(FankitId::new(42), &Site::default(), html)
+            .expect("should be parsable");
+        assert_eq!(info.ty(), "Wallpaper");
+        assert_eq!(info.title(), "Title");
+        assert_eq!(info.description(), None);
+        assert_eq!(info.published(), None);
+        assert_eq!(
+            info.image_urls().collect::<Vec<_>>(),
+            vec!["https://example.com/a.png"]
+        );
+    }
+
+    #[test]
+    fn relative_image_urls() {
+        let html = r#"<html><head><base href="https://cdn.example.com/uploads/"></head>
+        <body><div id="contents">
+            <p class="fankit-type">Wallpaper</p>
+            <h2 class="title">Title</h2>
+            <a href="a.png?v=1">a</a>
+            <a href="/b.jpg#top">b</a>
+            <a href="//example.com/c.png">c</a>
+            <a href="https://cdn.example.com/uploads/a.png?v=1#dup">a</a>
+        </div></body></html>"#;
+        let info = FankitInfo::parse_html(FankitId::new(42), &Site::default(), html)
+            .expect("should be parsable");
+        let mut urls = info.image_urls().collect::<Vec<_>>();
+        urls.sort_unstable();
+        assert_eq!(
+            urls,
+            vec![
+                "https://cdn.example.com/b.jpg",
+                "https://cdn.example.com/uploads/a.png?v=1",
+                "https://example.com/c.png",
+            ]
+        );
+    }
+
+    #[test]
+    fn optional_fields() {
+        let html = r#"<html><body><div id="contents">
+            <time datetime="2020-01-10">2020.01.10</time>
+            <p class="fankit-type">Wallpaper</p>
+            <h2 class="title">Title</h2>
+            <div class="description">
+                <p>First line.</p>
+                <p>Second line.</p>
+            </div>
+        </div></body></html>"#;
+        let info = FankitInfo::parse_html(FankitId::new(42), &Site::default(), html)
+            .expect("should be parsable");
+        assert_eq!(info.description(), Some("First line. Second line."));
+        assert_eq!(info.published(), Some("2020-01-10"));
+
+        let html = r#"<html><body><div id="contents">
+            <p class="fankit-type">Wallpaper</p>
+            <h2 class="title">Title</h2>
+            <span class="date"> 2020.01.10 </span>
+        </div></body></html>"#;
+        let info = FankitInfo::parse_html(FankitId::new(42), &Site::default(), html)
+            .expect("should be parsable");
+        assert_eq!(info.published(), Some("2020.01.10"));
+    }
+
+    #[test]
+    fn layout_changed() {
+        let html = r#"<html><body><div id="contents">
+            <p class="fankit-type">Wallpaper</p>
+        </div></body></html>"#;
+        match FankitInfo::parse_html(FankitId::new(42), &Site::default(), html) {
+            Err(Error::LayoutChanged { url, selector }) => {
+                assert_eq!(url, "https://priconne-redive.jp/fankit02/42/");
+                assert_eq!(selector, "#contents .title");
+            }
+            v => panic!("unexpected result: {:?}", v),
+        }
+    }
+}
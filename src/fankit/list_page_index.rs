@@ -2,13 +2,18 @@
 
 use std::{error, fmt};
 
+use markup5ever_rcdom::Handle;
+use url::Url;
+
 use crate::{
-    fankit::{FankitId, URL_FANKIT_LIST_BASE, URL_FANKIT_TOP},
-    node::{get_anchors, load_dom},
+    error::Error,
+    fankit::{FankitId, Site},
+    http::{block_on, HttpClient},
+    node::{document_base, get_anchors, load_dom_async, parse_dom},
 };
 
 /// Fankit list page index.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FankitListPageIndex(usize);
 
 impl FankitListPageIndex {
@@ -17,58 +22,110 @@ impl FankitListPageIndex {
         Self(v)
     }
 
-    /// Returns the URL of the fankit list page.
-    pub fn to_url(self) -> String {
+    /// Returns `usize` value.
+    pub fn to_usize(self) -> usize {
+        self.0
+    }
+
+    /// Returns the URL of the fankit list page in the site.
+    pub fn to_url(self, site: &Site) -> String {
         if self.0 <= 1 {
-            URL_FANKIT_TOP.to_owned()
+            site.top_url().to_owned()
         } else {
-            format!("{}{}/", URL_FANKIT_LIST_BASE, self.0)
+            format!("{}{}/", site.list_base(), self.0)
         }
     }
 
+    /// Parses the URL of the fankit list page in the site.
+    ///
+    /// Equivalent forms of the URL are accepted: `http` and `https` are not
+    /// distinguished, and the trailing slash, the query, and the fragment are
+    /// optional.
+    pub fn from_url(url: &str, site: &Site) -> Result<Self, FankitListPageIndexParseError> {
+        let url = Url::parse(url.trim()).map_err(|_| FankitListPageIndexParseError::InvalidUrl)?;
+        Self::from_parsed_url(&url, site)
+    }
+
+    /// Parses the URL of the fankit list page in the site.
+    fn from_parsed_url(url: &Url, site: &Site) -> Result<Self, FankitListPageIndexParseError> {
+        let relpath = site
+            .relative_path(url)
+            .ok_or(FankitListPageIndexParseError::BaseMismatch)?
+            .trim_end_matches('/');
+        if relpath.is_empty() {
+            return Ok(Self::new(1));
+        }
+        relpath
+            .strip_prefix("page/")
+            .ok_or(FankitListPageIndexParseError::InvalidPath)?
+            .parse::<usize>()
+            .map(Self::new)
+            .map_err(|_| FankitListPageIndexParseError::InvalidPath)
+    }
+
     /// Loads the list page, and returns the fankit ids and other list pages
-    /// found.
+    /// found, blocking the current thread.
+    ///
+    /// This must not be called from async context.
     pub fn load(
         self,
-    ) -> Result<(Vec<FankitId>, Vec<Self>), Box<dyn error::Error + Send + Sync + 'static>> {
+        client: &HttpClient,
+        site: &Site,
+    ) -> Result<(Vec<FankitId>, Vec<Self>), Error> {
+        block_on(self.load_async(client, site))?
+    }
+
+    /// Loads the list page, and returns the fankit ids and other list pages
+    /// found.
+    pub async fn load_async(
+        self,
+        client: &HttpClient,
+        site: &Site,
+    ) -> Result<(Vec<FankitId>, Vec<Self>), Error> {
         log::trace!("Loading list page: {:?}", self);
-        let dom = load_dom(&self.to_url())?;
+        let url = self.to_url(site);
+        let dom = load_dom_async(&url, client).await?;
 
+        Ok(Self::collect_links(dom.document, &url, site))
+    }
+
+    /// Parses the HTML of a list page, and returns the fankit ids and other
+    /// list pages found.
+    ///
+    /// Relative links are resolved against the top page of the site.
+    pub fn parse_html(html: &str, site: &Site) -> (Vec<FankitId>, Vec<Self>) {
+        Self::collect_links(parse_dom(html).document, site.top_url(), site)
+    }
+
+    /// Returns the fankit ids and list pages linked from the document at the
+    /// URL.
+    fn collect_links(document: Handle, url: &str, site: &Site) -> (Vec<FankitId>, Vec<Self>) {
         let mut fankits = Vec::new();
         let mut list_pages = Vec::new();
-        for href in get_anchors(dom.document).filter(|href| href.starts_with(URL_FANKIT_TOP)) {
-            if let Ok(fankit) = href.parse::<FankitId>() {
+        let base = match Url::parse(url) {
+            Ok(url) => document_base(&document, &url),
+            Err(e) => {
+                log::warn!("Invalid list page URL {:?}: {}", url, e);
+                return (fankits, list_pages);
+            }
+        };
+        for url in get_anchors(document, &base) {
+            if let Ok(fankit) = FankitId::from_parsed_url(&url, site) {
                 fankits.push(fankit);
-            } else if let Ok(list_page) = href.parse::<FankitListPageIndex>() {
+            } else if let Ok(list_page) = Self::from_parsed_url(&url, site) {
                 list_pages.push(list_page);
             }
         }
 
-        Ok((fankits, list_pages))
-    }
-}
-
-impl std::str::FromStr for FankitListPageIndex {
-    type Err = FankitListPageIndexParseError;
-
-    fn from_str(url: &str) -> Result<Self, Self::Err> {
-        if url == URL_FANKIT_TOP {
-            return Ok(Self::new(1));
-        }
-        if !url.starts_with(URL_FANKIT_LIST_BASE) {
-            return Err(FankitListPageIndexParseError::BaseMismatch);
-        }
-        let relpath = url[URL_FANKIT_LIST_BASE.len()..].trim_end_matches('/');
-        relpath
-            .parse::<usize>()
-            .map(Self::new)
-            .map_err(|_| FankitListPageIndexParseError::InvalidPath)
+        (fankits, list_pages)
     }
 }
 
 /// `FankitListPageIndex` parse error.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FankitListPageIndexParseError {
+    /// Invalid URL.
+    InvalidUrl,
     /// Base URL mismatch.
     BaseMismatch,
     /// Invalid path.
@@ -80,6 +137,7 @@ impl error::Error for FankitListPageIndexParseError {}
 impl fmt::Display for FankitListPageIndexParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::InvalidUrl => f.write_str("Invalid URL"),
             Self::BaseMismatch => f.write_str("Base URL mismatch"),
             Self::InvalidPath => f.write_str("Invalid path"),
         }
@@ -92,38 +150,76 @@ mod tests {
 
     #[test]
     fn to_url() {
+        let site = Site::default();
         assert_eq!(
-            FankitListPageIndex::new(1).to_url(),
+            FankitListPageIndex::new(1).to_url(&site),
             "https://priconne-redive.jp/fankit02/"
         );
         assert_eq!(
-            FankitListPageIndex::new(3).to_url(),
+            FankitListPageIndex::new(3).to_url(&site),
             "https://priconne-redive.jp/fankit02/page/3/"
         );
     }
 
     #[test]
     fn from_list_index() {
+        let site = Site::default();
+        let parse = |url| FankitListPageIndex::from_url(url, &site).ok();
         assert_eq!(
-            "https://priconne-redive.jp/fankit02/"
-                .parse::<FankitListPageIndex>()
-                .ok(),
+            parse("https://priconne-redive.jp/fankit02/"),
             Some(FankitListPageIndex::new(1))
         );
         assert_eq!(
-            "https://priconne-redive.jp/fankit02/page/3/"
-                .parse::<FankitListPageIndex>()
-                .ok(),
+            parse("https://priconne-redive.jp/fankit02/page/3/"),
             Some(FankitListPageIndex::new(3))
         );
         assert_eq!(
-            "https://priconne-redive.jp/fankit02/page/4/"
-                .parse::<FankitListPageIndex>()
-                .ok(),
+            parse("https://priconne-redive.jp/fankit02/page/4/"),
             Some(FankitListPageIndex::new(4))
         );
-        assert!("https://priconne-redive.jp/"
-            .parse::<FankitListPageIndex>()
-            .is_err());
+        assert_eq!(parse("https://priconne-redive.jp/"), None);
+        assert_eq!(
+            parse("http://priconne-redive.jp/fankit02"),
+            Some(FankitListPageIndex::new(1))
+        );
+        assert_eq!(
+            parse("https://priconne-redive.jp/fankit02/page/5?utm_source=x"),
+            Some(FankitListPageIndex::new(5))
+        );
+        assert_eq!(parse("https://priconne-redive.jp/fankit02/1234/"), None);
+    }
+
+    #[test]
+    fn parse_html() {
+        let html = r#"<html><body>
+            <a href="https://priconne-redive.jp/fankit02/1234/">item</a>
+            <a href="https://priconne-redive.jp/fankit02/page/2/">2</a>
+            <a href="https://example.com/">other</a>
+        </body></html>"#;
+        let (fankits, list_pages) = FankitListPageIndex::parse_html(html, &Site::default());
+        assert_eq!(fankits, vec![FankitId::new(1234)]);
+        assert_eq!(list_pages, vec![FankitListPageIndex::new(2)]);
+    }
+
+    #[test]
+    fn relative_links() {
+        let html = r#"<html><head><base href="/fankit02/page/2/"></head><body>
+            <a href="../../1234/?utm_source=list">item</a>
+            <a href="//priconne-redive.jp/fankit02/1235">item</a>
+            <a href="http://priconne-redive.jp/fankit02/1236/#top">item</a>
+            <a href="../3">3</a>
+            <a href="mailto:info@example.com">mail</a>
+        </body></html>"#;
+        let (mut fankits, list_pages) = FankitListPageIndex::parse_html(html, &Site::default());
+        fankits.sort();
+        assert_eq!(
+            fankits,
+            vec![
+                FankitId::new(1234),
+                FankitId::new(1235),
+                FankitId::new(1236)
+            ]
+        );
+        assert_eq!(list_pages, vec![FankitListPageIndex::new(3)]);
     }
 }
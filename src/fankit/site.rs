@@ -0,0 +1,165 @@
+//! Fankit site.
+
+use url::Url;
+
+use crate::error::Error;
+
+/// Default base URL of the site.
+pub const DEFAULT_BASE_URL: &str = "https://priconne-redive.jp/";
+
+/// Default fankit section.
+pub const DEFAULT_SECTION: &str = "fankit02";
+
+/// Fankit section in the site.
+///
+/// This determines the URLs of the fankit items and the list pages.
+///
+/// Fankit IDs are assumed to be unique across the sections in a site.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Site {
+    /// Section.
+    section: String,
+    /// Common URL prefix for fankit-related pages.
+    top_url: String,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Self::new(DEFAULT_BASE_URL, DEFAULT_SECTION).expect("the default site should be valid")
+    }
+}
+
+impl Site {
+    /// Creates a new site with the given base URL and section.
+    ///
+    /// The section is the path segment under the base URL, such as
+    /// `fankit02`.
+    /// The trailing slash of the base URL and the slashes around the section
+    /// are optional.
+    ///
+    /// Returns `Error::Config` if the base URL is not an absolute `http` or
+    /// `https` URL without a query and a fragment.
+    pub fn new(base_url: &str, section: &str) -> Result<Self, Error> {
+        let section = section.trim_matches('/');
+        let top_url = format!("{}/{}/", base_url.trim_end_matches('/'), section);
+        let invalid =
+            |reason: &str| Error::Config(format!("Invalid base URL {:?}: {}", base_url, reason));
+        let url = Url::parse(base_url).map_err(|e| invalid(&e.to_string()))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid("the scheme should be http or https"));
+        }
+        if url.query().is_some() || url.fragment().is_some() {
+            return Err(invalid("queries and fragments are not allowed"));
+        }
+        Ok(Self {
+            section: section.to_owned(),
+            top_url,
+        })
+    }
+
+    /// Returns the section.
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    /// Returns the URL of the top page of the fankits.
+    ///
+    /// This is also the common URL prefix for fankit-related pages.
+    pub fn top_url(&self) -> &str {
+        &self.top_url
+    }
+
+    /// Returns the URL prefix for fankit items.
+    pub(crate) fn item_base(&self) -> &str {
+        &self.top_url
+    }
+
+    /// Returns the URL prefix for fankit list pages.
+    pub(crate) fn list_base(&self) -> String {
+        format!("{}page/", self.top_url)
+    }
+
+    /// Returns the path of the URL relative to the top page, if the URL is
+    /// under the top page.
+    ///
+    /// `http` and `https` are treated as equivalent, and the query and the
+    /// fragment are ignored.
+    pub(crate) fn relative_path<'a>(&self, url: &'a Url) -> Option<&'a str> {
+        let top = Url::parse(&self.top_url).ok()?;
+        let is_http = |url: &Url| matches!(url.scheme(), "http" | "https");
+        if !is_http(url) || !is_http(&top) || url.host() != top.host() || url.port() != top.port() {
+            return None;
+        }
+        let rest = url.path().strip_prefix(top.path().trim_end_matches('/'))?;
+        if rest.is_empty() {
+            Some(rest)
+        } else {
+            rest.strip_prefix('/')
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_url() {
+        assert_eq!(
+            Site::default().top_url(),
+            "https://priconne-redive.jp/fankit02/"
+        );
+        assert_eq!(
+            Site::new("http://127.0.0.1:8080", "fankit02")
+                .unwrap()
+                .top_url(),
+            "http://127.0.0.1:8080/fankit02/"
+        );
+        assert_eq!(
+            Site::new("https://example.com/mirror/", "/fankit/")
+                .unwrap()
+                .top_url(),
+            "https://example.com/mirror/fankit/"
+        );
+    }
+
+    #[test]
+    fn invalid_base_url() {
+        for base_url in &[
+            "priconne-redive.jp",
+            "ftp://example.com/",
+            "https://",
+            "https://example.com/?mirror=1",
+        ] {
+            assert!(
+                matches!(Site::new(base_url, "fankit02"), Err(Error::Config(_))),
+                "{:?} should be invalid",
+                base_url
+            );
+        }
+    }
+
+    #[test]
+    fn relative_path() {
+        let site = Site::new("https://example.com/mirror", "fankit").unwrap();
+        let relative_path = |url| {
+            site.relative_path(&Url::parse(url).unwrap())
+                .map(str::to_owned)
+        };
+        assert_eq!(
+            relative_path("https://example.com/mirror/fankit/1/"),
+            Some("1/".to_owned())
+        );
+        assert_eq!(
+            relative_path("http://EXAMPLE.com:80/mirror/fankit?utm=x"),
+            Some("".to_owned())
+        );
+        assert_eq!(relative_path("https://example.com/mirror/fankit2/"), None);
+        assert_eq!(
+            relative_path("https://example.com:8080/mirror/fankit/"),
+            None
+        );
+        assert_eq!(relative_path("https://example.org/mirror/fankit/"), None);
+        assert_eq!(relative_path("ftp://example.com/mirror/fankit/"), None);
+    }
+}
@@ -0,0 +1,191 @@
+//! Fankits specified directly, bypassing the crawl.
+
+use std::{error, fmt, ops::RangeInclusive};
+
+use crate::fankit::{FankitId, Site};
+
+/// Maximum number of fankit IDs in a target range.
+///
+/// The IDs in the range are queued to the downloader at once, so that a typo
+/// such as `1..=100000000` must not be accepted.
+pub const MAX_TARGET_IDS: usize = 10_000;
+
+/// Fankits specified by an ID, an item URL, or an ID range.
+///
+/// IDs and ID ranges refer to the fankits in the first site given to
+/// [`FankitTarget::parse`], and item URLs refer to the fankit in the site
+/// matching the URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FankitTarget {
+    /// Site.
+    site: Site,
+    /// First ID.
+    first: FankitId,
+    /// Last ID, inclusive.
+    last: FankitId,
+}
+
+impl FankitTarget {
+    /// Parses a fankit ID (`100`), an item URL, or an ID range (`100..=150`
+    /// or `100..151`).
+    ///
+    /// Ranges with more than [`MAX_TARGET_IDS`] IDs are rejected.
+    pub fn parse(s: &str, sites: &[Site]) -> Result<Self, FankitTargetParseError> {
+        let s = s.trim();
+        if s.contains("://") {
+            return sites
+                .iter()
+                .find_map(|site| {
+                    let id = FankitId::from_url(s, site).ok()?;
+                    Some(Self {
+                        site: site.clone(),
+                        first: id,
+                        last: id,
+                    })
+                })
+                .ok_or(FankitTargetParseError::UnknownUrl);
+        }
+
+        let site = sites
+            .first()
+            .ok_or(FankitTargetParseError::UnknownUrl)?
+            .clone();
+        let ids = parse_id_range(s)?;
+        if ids.end().to_usize() - ids.start().to_usize() >= MAX_TARGET_IDS {
+            return Err(FankitTargetParseError::TooLargeRange);
+        }
+
+        Ok(Self {
+            site,
+            first: *ids.start(),
+            last: *ids.end(),
+        })
+    }
+
+    /// Returns the site.
+    pub fn site(&self) -> &Site {
+        &self.site
+    }
+
+    /// Returns the fankit IDs in ascending order.
+    pub fn ids(&self) -> impl Iterator<Item = FankitId> {
+        (self.first.to_usize()..=self.last.to_usize()).map(FankitId::new)
+    }
+}
+
+/// Parses a fankit ID (`100`) or an ID range (`100..=150` or `100..151`).
+pub fn parse_id_range(s: &str) -> Result<RangeInclusive<FankitId>, FankitTargetParseError> {
+    let parse_id = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| FankitTargetParseError::InvalidId)
+    };
+    let s = s.trim();
+    let (first, last) = if let Some((first, last)) = s.split_once("..=") {
+        (parse_id(first)?, parse_id(last)?)
+    } else if let Some((first, end)) = s.split_once("..") {
+        let end = parse_id(end)?;
+        if end == 0 {
+            return Err(FankitTargetParseError::EmptyRange);
+        }
+        (parse_id(first)?, end - 1)
+    } else {
+        let id = parse_id(s)?;
+        (id, id)
+    };
+    if first > last {
+        return Err(FankitTargetParseError::EmptyRange);
+    }
+
+    Ok(FankitId::new(first)..=FankitId::new(last))
+}
+
+/// `FankitTarget` parse error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FankitTargetParseError {
+    /// Invalid fankit ID.
+    InvalidId,
+    /// The range has no fankit IDs.
+    EmptyRange,
+    /// The range has more than [`MAX_TARGET_IDS`] fankit IDs.
+    TooLargeRange,
+    /// The URL is not a fankit item in the sites.
+    UnknownUrl,
+}
+
+impl error::Error for FankitTargetParseError {}
+
+impl fmt::Display for FankitTargetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidId => f.write_str("Invalid fankit ID"),
+            Self::EmptyRange => f.write_str("Empty fankit ID range"),
+            Self::TooLargeRange => write!(
+                f,
+                "Too large fankit ID range (at most {} IDs)",
+                MAX_TARGET_IDS
+            ),
+            Self::UnknownUrl => f.write_str("Not a fankit item URL in the sections"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(s: &str, sites: &[Site]) -> Result<Vec<usize>, FankitTargetParseError> {
+        FankitTarget::parse(s, sites).map(|target| target.ids().map(FankitId::to_usize).collect())
+    }
+
+    #[test]
+    fn parse() {
+        let sites = [
+            Site::default(),
+            Site::new("https://priconne-redive.jp/", "fankit01").unwrap(),
+        ];
+        assert_eq!(ids("100", &sites), Ok(vec![100]));
+        assert_eq!(ids("100..=102", &sites), Ok(vec![100, 101, 102]));
+        assert_eq!(ids("100..102", &sites), Ok(vec![100, 101]));
+        assert_eq!(ids("100..=100", &sites), Ok(vec![100]));
+        assert_eq!(
+            ids("102..=100", &sites),
+            Err(FankitTargetParseError::EmptyRange)
+        );
+        assert_eq!(
+            ids("100..100", &sites),
+            Err(FankitTargetParseError::EmptyRange)
+        );
+        assert_eq!(ids("0..0", &sites), Err(FankitTargetParseError::EmptyRange));
+        assert_eq!(ids("abc", &sites), Err(FankitTargetParseError::InvalidId));
+        assert_eq!(
+            ids("1..=10000", &sites).map(|ids| ids.len()),
+            Ok(MAX_TARGET_IDS)
+        );
+        assert_eq!(
+            ids("1..=100000000", &sites),
+            Err(FankitTargetParseError::TooLargeRange)
+        );
+        assert_eq!(
+            ids("100..=", &sites),
+            Err(FankitTargetParseError::InvalidId)
+        );
+
+        let target = FankitTarget::parse("https://priconne-redive.jp/fankit01/50/", &sites)
+            .expect("item URL should be parsed");
+        assert_eq!(target.site().section(), "fankit01");
+        assert_eq!(target.ids().collect::<Vec<_>>(), vec![FankitId::new(50)]);
+        assert_eq!(
+            FankitTarget::parse("100", &sites).map(|target| target.site().clone()),
+            Ok(Site::default())
+        );
+        assert_eq!(
+            ids("https://priconne-redive.jp/fankit03/50/", &sites),
+            Err(FankitTargetParseError::UnknownUrl)
+        );
+        assert_eq!(
+            ids("https://priconne-redive.jp/fankit02/page/2/", &sites),
+            Err(FankitTargetParseError::UnknownUrl)
+        );
+    }
+}
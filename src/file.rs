@@ -0,0 +1,330 @@
+//! File utilities.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Suffix of temporary files.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Suffix of partially downloaded files.
+const PART_SUFFIX: &str = ".part";
+
+/// Suffix of files which store validators of partially downloaded files.
+const VALIDATOR_SUFFIX: &str = ".part.validator";
+
+/// Returns the given path with the suffix appended.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut new_path = OsString::from(path.as_os_str());
+    new_path.push(suffix);
+    new_path.into()
+}
+
+/// Returns the path of the temporary file for the given path.
+fn temp_path(path: &Path) -> PathBuf {
+    with_suffix(path, TEMP_SUFFIX)
+}
+
+/// Syncs the directory to persist renames in it.
+fn sync_parent_dir(path: &Path) {
+    if let Some(parent) = path.parent() {
+        if let Err(e) = File::open(parent).and_then(|dir| dir.sync_all()) {
+            log::debug!("Failed to sync the directory {}: {}", parent.display(), e);
+        }
+    }
+}
+
+/// Removes the file if exists.
+fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Writes to the file atomically.
+///
+/// The content is written to a temporary file next to `out_path`, and the
+/// temporary file is renamed to `out_path` only after the whole content is
+/// written and synced.
+/// On failure, the temporary file is removed and `out_path` is left untouched.
+pub fn write_atomically<F>(out_path: &Path, f: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let temp_path = temp_path(out_path);
+    let result = write_and_sync(&temp_path, f).and_then(|()| fs::rename(&temp_path, out_path));
+    if let Err(e) = result {
+        if let Err(e) = remove_file_if_exists(&temp_path) {
+            log::warn!(
+                "Failed to remove the temporary file {}: {}",
+                temp_path.display(),
+                e
+            );
+        }
+        return Err(e);
+    }
+    sync_parent_dir(out_path);
+
+    Ok(())
+}
+
+/// Writes to the file with buffering, and syncs it.
+fn write_and_sync<F>(out_path: &Path, f: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    // Create the file and the writer.
+    let file = match File::create(out_path) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to create a file {}: {}", out_path.display(), e);
+            return Err(e);
+        }
+    };
+    let mut writer = BufWriter::new(file);
+
+    // Do the job.
+    f(&mut writer)?;
+
+    // Flush the writer.
+    let file = match writer.into_inner() {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Failed to finalize the buffer: {}", e);
+            return Err(e.into_error());
+        }
+    };
+
+    // Sync the file.
+    if let Err(e) = file.sync_all() {
+        log::error!(
+            "Failed to sync the output file {}: {}",
+            out_path.display(),
+            e
+        );
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Partially downloaded file.
+///
+/// The content is stored in `{out_path}.part`, and the validator (`ETag` or
+/// `Last-Modified` value) of the content is stored in
+/// `{out_path}.part.validator`, so that the download can be resumed with
+/// `If-Range` header even after the process is killed.
+#[derive(Debug, Clone)]
+pub struct PartFile {
+    /// Final path.
+    out_path: PathBuf,
+    /// Path of the partial content.
+    part_path: PathBuf,
+    /// Path of the validator.
+    validator_path: PathBuf,
+}
+
+impl PartFile {
+    /// Creates a new `PartFile` for the given final path.
+    pub fn new(out_path: &Path) -> Self {
+        Self {
+            out_path: out_path.to_owned(),
+            part_path: with_suffix(out_path, PART_SUFFIX),
+            validator_path: with_suffix(out_path, VALIDATOR_SUFFIX),
+        }
+    }
+
+    /// Returns the path of the partial content.
+    pub fn part_path(&self) -> &Path {
+        &self.part_path
+    }
+
+    /// Returns the length of the partial content and its validator, if the
+    /// download can be resumed.
+    pub fn resume_point(&self) -> io::Result<Option<(u64, String)>> {
+        let len = match fs::metadata(&self.part_path) {
+            Ok(meta) if meta.len() > 0 => meta.len(),
+            Ok(_) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match fs::read_to_string(&self.validator_path) {
+            Ok(validator) if !validator.trim().is_empty() => {
+                Ok(Some((len, validator.trim().to_owned())))
+            }
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Opens the part file to write the content from the given offset.
+    ///
+    /// If `offset` is zero, the partial content is truncated and the new
+    /// validator is stored.
+    pub fn open(&self, offset: u64, validator: Option<&str>) -> io::Result<File> {
+        if offset == 0 {
+            remove_file_if_exists(&self.validator_path)?;
+            let file = File::create(&self.part_path)?;
+            if let Some(validator) = validator {
+                write_atomically(&self.validator_path, |writer| {
+                    writer.write_all(validator.as_bytes())
+                })?;
+            }
+            return Ok(file);
+        }
+
+        let file = fs::OpenOptions::new().append(true).open(&self.part_path)?;
+        let len = file.metadata()?.len();
+        if len != offset {
+            return Err(io::Error::other(format!(
+                "Partial content {} has changed: expected {} bytes, found {} bytes",
+                self.part_path.display(),
+                offset,
+                len
+            )));
+        }
+        Ok(file)
+    }
+
+    /// Renames the complete content to the final path.
+    pub fn persist(&self) -> io::Result<()> {
+        fs::rename(&self.part_path, &self.out_path)?;
+        sync_parent_dir(&self.out_path);
+        remove_file_if_exists(&self.validator_path)
+    }
+
+    /// Removes the partial content and the validator.
+    pub fn discard(&self) -> io::Result<()> {
+        remove_file_if_exists(&self.part_path)?;
+        remove_file_if_exists(&self.validator_path)
+    }
+}
+
+/// Returns whether the path is a temporary file or a partially downloaded
+/// file.
+pub(crate) fn is_temp_file(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    [TEMP_SUFFIX, PART_SUFFIX, VALIDATOR_SUFFIX]
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
+
+/// Removes stale temporary files left by interrupted runs.
+///
+/// Temporary files under `dest_dir` are removed, at any depth since item
+/// directories and image files may be nested by the name templates.
+/// Symbolic links are not followed.
+/// Partially downloaded files are kept to resume later.
+pub fn remove_temp_files(dest_dir: &Path) -> io::Result<()> {
+    let mut dirs = vec![dest_dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            let is_temp = entry.file_name().to_string_lossy().ends_with(TEMP_SUFFIX);
+            if !is_temp || !file_type.is_file() {
+                continue;
+            }
+            let path = entry.path();
+            log::info!("Removing stale temporary file {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                log::warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty temporary directory for the test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "priconne-fankit-dl-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("failed to create a test directory");
+        dir
+    }
+
+    #[test]
+    fn failed_write_leaves_nothing() {
+        let dir = test_dir("failed-write");
+        let path = dir.join("image.png");
+
+        let result = write_atomically(&path, |writer| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(result.is_err());
+        assert!(!path.exists());
+        assert!(!temp_path(&path).exists());
+
+        write_atomically(&path, |writer| writer.write_all(b"complete"))
+            .expect("writing should succeed");
+        assert_eq!(fs::read(&path).expect("file should exist"), b"complete");
+
+        fs::remove_dir_all(&dir).expect("failed to clean up the test directory");
+    }
+
+    #[test]
+    fn part_file_resume() {
+        let dir = test_dir("part-file");
+        let path = dir.join("image.png");
+        let part = PartFile::new(&path);
+        assert_eq!(part.resume_point().expect("should be readable"), None);
+
+        part.open(0, Some("\"etag\""))
+            .and_then(|mut file| file.write_all(b"abc"))
+            .expect("writing should succeed");
+        assert_eq!(
+            part.resume_point().expect("should be readable"),
+            Some((3, "\"etag\"".to_owned()))
+        );
+        assert!(part.open(2, None).is_err());
+
+        part.open(3, None)
+            .and_then(|mut file| file.write_all(b"def"))
+            .expect("writing should succeed");
+        part.persist().expect("persisting should succeed");
+        assert_eq!(fs::read(&path).expect("file should exist"), b"abcdef");
+        assert_eq!(part.resume_point().expect("should be readable"), None);
+
+        fs::remove_dir_all(&dir).expect("failed to clean up the test directory");
+    }
+
+    #[test]
+    fn stale_temp_files_are_removed() {
+        let dir = test_dir("stale-temp");
+        let item_dir = dir.join("1-item");
+        fs::create_dir(&item_dir).expect("failed to create an item directory");
+        fs::write(item_dir.join("a.png"), b"image").expect("failed to write an image");
+        fs::write(item_dir.join("b.png.tmp"), b"partial").expect("failed to write a temp file");
+        fs::write(item_dir.join("c.png.part"), b"partial").expect("failed to write a part file");
+        let nested_dir = dir.join("2-type/title/pc");
+        fs::create_dir_all(&nested_dir).expect("failed to create a nested directory");
+        fs::write(nested_dir.join("d.png.tmp"), b"partial").expect("failed to write a temp file");
+
+        remove_temp_files(&dir).expect("removing temp files should succeed");
+        assert!(item_dir.join("a.png").exists());
+        assert!(!item_dir.join("b.png.tmp").exists());
+        assert!(item_dir.join("c.png.part").exists());
+        assert!(!nested_dir.join("d.png.tmp").exists());
+
+        fs::remove_dir_all(&dir).expect("failed to clean up the test directory");
+    }
+}
@@ -0,0 +1,181 @@
+//! Fankit filter.
+
+use std::ops::RangeInclusive;
+
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+
+use crate::{
+    error::Error,
+    fankit::{FankitId, FankitInfo},
+};
+
+/// Pattern of fankit titles.
+#[derive(Debug, Clone)]
+pub enum TitlePattern {
+    /// Glob pattern matching the whole title.
+    Glob(GlobMatcher),
+    /// Regular expression matching a part of the title.
+    Regex(Regex),
+}
+
+impl TitlePattern {
+    /// Creates a glob pattern matching the whole title.
+    pub fn glob(pattern: &str) -> Result<Self, Error> {
+        Glob::new(pattern)
+            .map(|glob| Self::Glob(glob.compile_matcher()))
+            .map_err(|e| Error::Config(format!("Invalid title glob {:?}: {}", pattern, e)))
+    }
+
+    /// Creates a regular expression pattern matching a part of the title.
+    pub fn regex(pattern: &str) -> Result<Self, Error> {
+        Regex::new(pattern)
+            .map(Self::Regex)
+            .map_err(|e| Error::Config(format!("Invalid title regex {:?}: {}", pattern, e)))
+    }
+
+    /// Returns true if the title matches the pattern.
+    pub fn is_match(&self, title: &str) -> bool {
+        match self {
+            Self::Glob(glob) => glob.is_match(title),
+            Self::Regex(regex) => regex.is_match(title),
+        }
+    }
+}
+
+/// Include and exclude rules of a criterion.
+#[derive(Debug, Clone)]
+struct Rules<T> {
+    /// Rules one of which must match, if any.
+    include: Vec<T>,
+    /// Rules none of which must match.
+    exclude: Vec<T>,
+}
+
+impl<T> Default for Rules<T> {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+impl<T> Rules<T> {
+    /// Returns true if the value is accepted by the rules.
+    fn accepts(&self, is_match: impl Fn(&T) -> bool) -> bool {
+        (self.include.is_empty() || self.include.iter().any(&is_match))
+            && !self.exclude.iter().any(is_match)
+    }
+}
+
+/// Filter of fankits to download.
+///
+/// A fankit is downloaded if it matches one of the included rules (if any)
+/// and none of the excluded rules, for each of the IDs, the types, and the
+/// titles.
+#[derive(Debug, Clone, Default)]
+pub struct FankitFilter {
+    /// ID ranges.
+    ids: Rules<RangeInclusive<FankitId>>,
+    /// Exact fankit types.
+    types: Rules<String>,
+    /// Title patterns.
+    titles: Rules<TitlePattern>,
+}
+
+impl FankitFilter {
+    /// Includes the fankits in the ID range.
+    pub fn include_ids(mut self, ids: RangeInclusive<FankitId>) -> Self {
+        self.ids.include.push(ids);
+        self
+    }
+
+    /// Excludes the fankits in the ID range.
+    pub fn exclude_ids(mut self, ids: RangeInclusive<FankitId>) -> Self {
+        self.ids.exclude.push(ids);
+        self
+    }
+
+    /// Includes the fankits of the type.
+    pub fn include_type(mut self, ty: &str) -> Self {
+        self.types.include.push(ty.to_owned());
+        self
+    }
+
+    /// Excludes the fankits of the type.
+    pub fn exclude_type(mut self, ty: &str) -> Self {
+        self.types.exclude.push(ty.to_owned());
+        self
+    }
+
+    /// Includes the fankits with the title matching the pattern.
+    pub fn include_title(mut self, pattern: TitlePattern) -> Self {
+        self.titles.include.push(pattern);
+        self
+    }
+
+    /// Excludes the fankits with the title matching the pattern.
+    pub fn exclude_title(mut self, pattern: TitlePattern) -> Self {
+        self.titles.exclude.push(pattern);
+        self
+    }
+
+    /// Returns true if the fankit ID is accepted.
+    ///
+    /// This can be checked before the fankit page is loaded.
+    pub fn accepts_id(&self, id: FankitId) -> bool {
+        self.ids.accepts(|ids| ids.contains(&id))
+    }
+
+    /// Returns true if the fankit is accepted.
+    pub fn accepts(&self, info: &FankitInfo) -> bool {
+        self.accepts_id(info.id())
+            && self.types.accepts(|ty| ty == info.ty())
+            && self
+                .titles
+                .accepts(|pattern| pattern.is_match(info.title()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_all_by_default() {
+        assert!(FankitFilter::default().accepts(&FankitInfo::fixture(1, "壁紙", "ペコリーヌ")));
+    }
+
+    #[test]
+    fn ids() {
+        let filter = FankitFilter::default()
+            .include_ids(FankitId::new(100)..=FankitId::new(150))
+            .exclude_ids(FankitId::new(120)..=FankitId::new(120));
+        assert!(filter.accepts_id(FankitId::new(100)));
+        assert!(filter.accepts_id(FankitId::new(150)));
+        assert!(!filter.accepts_id(FankitId::new(99)));
+        assert!(!filter.accepts_id(FankitId::new(120)));
+    }
+
+    #[test]
+    fn types_and_titles() {
+        let filter = FankitFilter::default()
+            .include_type("壁紙")
+            .include_title(TitlePattern::glob("ペコ*").unwrap())
+            .include_title(TitlePattern::regex("キャル").unwrap())
+            .exclude_title(TitlePattern::regex("水着").unwrap());
+        assert!(filter.accepts(&FankitInfo::fixture(1, "壁紙", "ペコリーヌ")));
+        assert!(filter.accepts(&FankitInfo::fixture(2, "壁紙", "ニューイヤーキャル")));
+        assert!(!filter.accepts(&FankitInfo::fixture(3, "アイコン", "ペコリーヌ")));
+        assert!(!filter.accepts(&FankitInfo::fixture(4, "壁紙", "コッコロ")));
+        assert!(!filter.accepts(&FankitInfo::fixture(5, "壁紙", "ペコリーヌ（水着）")));
+        assert!(!filter.accepts(&FankitInfo::fixture(6, "壁紙", "水着ペコ")));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(matches!(TitlePattern::regex("("), Err(Error::Config(_))));
+        assert!(matches!(TitlePattern::glob("[a"), Err(Error::Config(_))));
+    }
+}
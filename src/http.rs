@@ -0,0 +1,265 @@
+//! HTTP client.
+
+use std::{fmt, future::Future, io, sync::Arc, time::Duration};
+
+use reqwest::{
+    cookie::Jar,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Client, Proxy, RequestBuilder, Response, Url,
+};
+
+use crate::{
+    error::Error,
+    rate_limit::{RateLimiter, RequestKind},
+    retry::{HttpStatusError, RetryPolicy},
+};
+
+/// Default user agent.
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// HTTP client configuration.
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    /// Connect timeout.
+    connect_timeout: Option<Duration>,
+    /// Read timeout.
+    read_timeout: Option<Duration>,
+    /// Proxy URL.
+    proxy: Option<String>,
+    /// User agent.
+    user_agent: Option<String>,
+    /// Extra headers.
+    headers: Vec<(String, String)>,
+    /// Initial cookies, as pairs of URL and `Set-Cookie` value.
+    cookies: Vec<(String, String)>,
+}
+
+impl HttpConfig {
+    /// Sets the connect timeout.
+    pub fn connect_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            connect_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Sets the read timeout.
+    ///
+    /// This limits the wait for the response headers and for each chunk of
+    /// the body, not the whole transfer.
+    pub fn read_timeout(self, timeout: Option<Duration>) -> Self {
+        Self {
+            read_timeout: timeout,
+            ..self
+        }
+    }
+
+    /// Sets the proxy URL.
+    ///
+    /// `http`, `https`, `socks5`, and `socks5h` schemes are supported.
+    pub fn proxy(self, proxy: Option<String>) -> Self {
+        Self { proxy, ..self }
+    }
+
+    /// Sets the user agent.
+    pub fn user_agent(self, user_agent: Option<String>) -> Self {
+        Self { user_agent, ..self }
+    }
+
+    /// Adds an extra header sent with every request.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Adds an initial cookie for the given URL.
+    ///
+    /// `cookie` is in the format of `Set-Cookie` header value.
+    pub fn cookie(mut self, url: &str, cookie: &str) -> Self {
+        self.cookies.push((url.to_owned(), cookie.to_owned()));
+        self
+    }
+}
+
+/// HTTP client shared among the requests.
+///
+/// This is cheap to clone, and clones share the connection pool, the cookie
+/// store, and the rate limiter.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    /// Client.
+    client: Client,
+    /// Retry policy.
+    retry: RetryPolicy,
+    /// Rate limiter.
+    rate_limiter: Arc<RateLimiter>,
+    /// Read timeout.
+    read_timeout: Option<Duration>,
+}
+
+impl HttpClient {
+    /// Creates a new HTTP client.
+    pub fn new(
+        config: &HttpConfig,
+        retry: RetryPolicy,
+        rate_limiter: RateLimiter,
+    ) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let invalid =
+                |e: &dyn fmt::Display| Error::Config(format!("Invalid header {:?}: {}", name, e));
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(&e))?,
+                HeaderValue::from_str(value).map_err(|e| invalid(&e))?,
+            );
+        }
+
+        let jar = Jar::default();
+        for (url, cookie) in &config.cookies {
+            let url = url
+                .parse::<Url>()
+                .map_err(|e| Error::Config(format!("Invalid cookie URL {:?}: {}", url, e)))?;
+            jar.add_cookie_str(cookie, &url);
+        }
+
+        let mut builder = Client::builder()
+            .user_agent(config.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT))
+            .default_headers(headers)
+            .cookie_provider(Arc::new(jar));
+        if let Some(timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &config.proxy {
+            let proxy = Proxy::all(proxy)
+                .map_err(|e| Error::Config(format!("Invalid proxy {:?}: {}", proxy, e)))?;
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(Self {
+            client: builder
+                .build()
+                .map_err(|e| Error::Config(format!("Failed to build HTTP client: {}", e)))?,
+            retry,
+            rate_limiter: Arc::new(rate_limiter),
+            read_timeout: config.read_timeout,
+        })
+    }
+
+    /// Returns the retry policy.
+    pub fn retry(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Starts building a GET request.
+    ///
+    /// This waits until the rate limiter allows the request, so the request
+    /// should be sent immediately.
+    pub async fn get(&self, url: &str, kind: RequestKind) -> RequestBuilder {
+        self.rate_limiter.wait(url, kind).await;
+        self.client.get(url)
+    }
+
+    /// Sends the request, and waits for the response headers within the read
+    /// timeout.
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response, Error> {
+        let req = req.build().map_err(|e| {
+            let url = e.url().map_or_else(String::new, ToString::to_string);
+            Error::network(&url, e)
+        })?;
+        let url = req.url().to_string();
+        self.timed(&url, self.client.execute(req))
+            .await?
+            .map_err(|e| Error::network(&url, e))
+    }
+
+    /// Runs the I/O operation for the URL within the read timeout.
+    pub async fn timed<F: Future>(&self, url: &str, fut: F) -> Result<F::Output, Error> {
+        match self.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, fut).await.map_err(|_| {
+                Error::network(
+                    url,
+                    io::Error::new(io::ErrorKind::TimedOut, "Read timed out"),
+                )
+            }),
+            None => Ok(fut.await),
+        }
+    }
+
+    /// Fetches the text content of the given URL, with retries.
+    ///
+    /// The read timeout applies to each chunk of the body, so that a slow
+    /// but progressing response is not aborted.
+    /// The content is decoded as UTF-8, replacing invalid sequences.
+    pub async fn fetch_text(&self, url: &str) -> Result<String, Error> {
+        self.retry
+            .run(url, || async {
+                let req = self.get(url, RequestKind::Page).await;
+                let mut resp = HttpStatusError::check(self.send(req).await?)?;
+                let mut body = Vec::new();
+                while let Some(chunk) = self
+                    .timed(url, resp.chunk())
+                    .await?
+                    .map_err(|e| Error::network(url, e))?
+                {
+                    body.extend_from_slice(&chunk);
+                }
+                Ok(String::from_utf8_lossy(&body).into_owned())
+            })
+            .await
+    }
+}
+
+/// Runs the future to completion on a new runtime, blocking the current
+/// thread.
+///
+/// This must not be called from async context.
+pub(crate) fn block_on<F: Future>(fut: F) -> Result<F::Output, Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::internal)?;
+    Ok(runtime.block_on(fut))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use crate::rate_limit::Rate;
+
+    use super::*;
+
+    #[test]
+    fn read_timeout_applies_to_each_chunk() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        // The whole body takes longer than the read timeout, but each chunk
+        // does not.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 1024]).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            for chunk in [b"ab", b"cd", b"ef", b"gh"] {
+                thread::sleep(Duration::from_millis(100));
+                stream.write_all(chunk).unwrap();
+            }
+        });
+
+        let rate = Rate::new(Duration::from_millis(0), 1);
+        let client = HttpClient::new(
+            &HttpConfig::default().read_timeout(Some(Duration::from_millis(250))),
+            RetryPolicy::default().max_attempts(1),
+            RateLimiter::new(rate, rate),
+        )
+        .unwrap();
+        let text = block_on(client.fetch_text(&url)).unwrap();
+        assert_eq!(text.unwrap(), "abcdefgh");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+//! Fankits downloader for Princess Connect Re:Dive.
+//!
+//! This crate provides the crawler of fankit list pages, the parser of fankit
+//! item pages, and the downloader of fankit images, which are used by the
+//! `priconne-fankit-dl` command.
+//!
+//! The core is async, and blocking wrappers are provided for convenience.
+//! Blocking functions must not be called from async context.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use priconne_fankit_dl::{
+//!     fankit::{FankitId, Site},
+//!     http::{HttpClient, HttpConfig},
+//!     rate_limit::{Rate, RateLimiter},
+//!     retry::RetryPolicy,
+//! };
+//! use std::time::Duration;
+//!
+//! # fn main() -> priconne_fankit_dl::Result<()> {
+//! let rate = Rate::new(Duration::from_secs(1), 1);
+//! let client = HttpClient::new(
+//!     &HttpConfig::default(),
+//!     RetryPolicy::default(),
+//!     RateLimiter::new(rate, rate),
+//! )?;
+//! let info = FankitId::new(1234).load(&client, &Site::default())?;
+//! for url in info.image_urls() {
+//!     println!("{}", url);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+#![warn(missing_docs)]
+
+pub use self::error::{Error, Result};
+
+mod checksum;
+pub mod download;
+mod error;
+pub mod event;
+pub mod fankit;
+pub mod file;
+pub mod filter;
+pub mod http;
+pub mod manifest;
+pub mod naming;
+mod node;
+pub mod plan;
+pub mod rate_limit;
+pub mod retry;
+pub mod run;
+pub mod sidecar;
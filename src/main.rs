@@ -1,30 +1,286 @@
-use std::{
-    borrow::Cow,
-    collections::HashSet,
-    fs::{self, File},
-    io::{self, BufWriter, Write},
-    path::{Path, PathBuf},
-    time::Duration,
-};
+use std::{path::PathBuf, process::ExitCode, time::Duration};
 
+use priconne_fankit_dl::{
+    download::DownloadConfig,
+    event::Summary,
+    fankit::{parse_id_range, AssetKind, FankitTarget, Site, DEFAULT_BASE_URL, DEFAULT_SECTION},
+    filter::{FankitFilter, TitlePattern},
+    http::{HttpClient, HttpConfig},
+    naming::{NameTemplate, DEFAULT_DIR_TEMPLATE, DEFAULT_FILE_TEMPLATE},
+    rate_limit::{Rate, RateLimiter},
+    retry::RetryPolicy,
+    run::{run, OutputFormat, RunConfig},
+    Error,
+};
 use structopt::StructOpt;
 
-use self::fankit::{get_fankits_if_new_fankit_found, FankitId};
-
-mod fankit;
-mod node;
-
-type BoxedError = Box<dyn std::error::Error + Send + Sync + 'static>;
-
 /// Fankits downloader for Princess Connect Re:Dive.
 #[derive(Debug, Clone, PartialEq, Eq, Hash, StructOpt)]
 pub struct CliOpt {
     /// Destination directory
     #[structopt(short, long, parse(from_os_str))]
     dest: Option<PathBuf>,
-    /// Crawl delay in milliseconds
+    /// Base URL of the site
+    #[structopt(long, default_value = DEFAULT_BASE_URL)]
+    base_url: String,
+    /// Fankit sections to crawl, such as `fankit02`
+    #[structopt(long = "section", number_of_values = 1, default_value = DEFAULT_SECTION)]
+    sections: Vec<String>,
+    /// Walk all list pages and download every fankit missing from the
+    /// destination, instead of stopping when the first list page has no new
+    /// fankits
+    #[structopt(long, conflicts_with = "FANKIT")]
+    full: bool,
+    /// Crawl and load the item pages, and print the fankits and the images
+    /// which would be downloaded, without writing anything
+    #[structopt(long)]
+    dry_run: bool,
+    /// Output format of the progress on the standard output (`text` or
+    /// `json`)
+    ///
+    /// In `json` mode, one JSON object is printed per line for each event,
+    /// followed by the summary.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    output: OutputFormat,
+    /// Write the summary report in JSON to the file, relative to the
+    /// destination directory
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+    /// Number of consecutive list pages with only known fankits after which
+    /// the incremental crawl stops
+    #[structopt(long, default_value = "1")]
+    known_pages_to_stop: usize,
+    /// Download only the fankits of the type, such as `壁紙`
+    #[structopt(long = "include-type", number_of_values = 1)]
+    include_types: Vec<String>,
+    /// Do not download the fankits of the type
+    #[structopt(long = "exclude-type", number_of_values = 1)]
+    exclude_types: Vec<String>,
+    /// Download only the fankits with the title matching the glob pattern
+    #[structopt(long = "include-title", number_of_values = 1)]
+    include_titles: Vec<String>,
+    /// Do not download the fankits with the title matching the glob pattern
+    #[structopt(long = "exclude-title", number_of_values = 1)]
+    exclude_titles: Vec<String>,
+    /// Download only the fankits with the title matching the regular
+    /// expression
+    #[structopt(long = "include-title-regex", number_of_values = 1)]
+    include_title_regexes: Vec<String>,
+    /// Do not download the fankits with the title matching the regular
+    /// expression
+    #[structopt(long = "exclude-title-regex", number_of_values = 1)]
+    exclude_title_regexes: Vec<String>,
+    /// Download only the fankits with the ID or in the ID range, such as
+    /// `100..=150`
+    #[structopt(long = "include-ids", number_of_values = 1)]
+    include_ids: Vec<String>,
+    /// Do not download the fankits with the ID or in the ID range
+    #[structopt(long = "exclude-ids", number_of_values = 1)]
+    exclude_ids: Vec<String>,
+    /// Template of new item directories, such as `{id:05}-{type}/{title}`.
+    /// Available fields are `{id}` (required), `{section}`, `{type}`, and
+    /// `{title}`
+    #[structopt(long, default_value = DEFAULT_DIR_TEMPLATE)]
+    dir_template: String,
+    /// Template of new image files in the item directory, such as
+    /// `{position:02}-{label}.{ext}`. `{filename}`, `{stem}`, `{ext}`,
+    /// `{position}`, and `{label}` are available in addition to the item
+    /// fields
+    #[structopt(long, default_value = DEFAULT_FILE_TEMPLATE)]
+    file_template: String,
+    /// Number of parent path segments of the image URLs kept in the image
+    /// file names, such as `pc_01.png` for `.../pc/01.png` with 1
+    #[structopt(long, default_value = "0")]
+    parent_segments: usize,
+    /// Kinds of assets to download: `image` (jpg, jpeg, png, gif, and webp),
+    /// `archive` (zip), and `document` (pdf)
+    #[structopt(
+        long,
+        require_delimiter = true,
+        default_value = "image,archive,document",
+        possible_values = &["image", "archive", "document"]
+    )]
+    asset_kinds: Vec<AssetKind>,
+    /// Minimum interval between page requests to a host, in milliseconds
     #[structopt(long, default_value = "1000")]
     delay: u64,
+    /// Average interval between image requests to a host, in milliseconds
+    #[structopt(long, default_value = "250")]
+    image_delay: u64,
+    /// Number of parallel image downloads
+    #[structopt(short, long, default_value = "4")]
+    jobs: usize,
+    /// Maximum number of attempts for each page and image
+    #[structopt(long, default_value = "3")]
+    max_attempts: u32,
+    /// Backoff before the first retry in milliseconds, doubled for each retry
+    #[structopt(long, default_value = "1000")]
+    retry_backoff: u64,
+    /// Maximum backoff between retries in milliseconds. Requests asked to
+    /// wait longer by `Retry-After` are not retried
+    #[structopt(long, default_value = "60000")]
+    retry_max_backoff: u64,
+    /// Random jitter of the backoff in percent
+    #[structopt(long, default_value = "50")]
+    retry_jitter: u8,
+    /// Retryable HTTP status codes
+    #[structopt(
+        long,
+        require_delimiter = true,
+        default_value = "408,429,500,502,503,504"
+    )]
+    retry_status: Vec<u16>,
+    /// Do not retry on network errors
+    #[structopt(long)]
+    no_retry_network: bool,
+    /// Connect timeout in milliseconds (0 to disable)
+    #[structopt(long, default_value = "30000")]
+    connect_timeout: u64,
+    /// Read timeout for the response headers and each chunk of the body, in
+    /// milliseconds (0 to disable)
+    #[structopt(long, default_value = "30000")]
+    read_timeout: u64,
+    /// Proxy URL (http, https, socks5, or socks5h)
+    #[structopt(long)]
+    proxy: Option<String>,
+    /// User agent
+    #[structopt(long)]
+    user_agent: Option<String>,
+    /// Extra header sent with every request, in `Name: value` format
+    #[structopt(long = "header", number_of_values = 1)]
+    headers: Vec<String>,
+    /// Cookie sent to the site, in `name=value` format
+    #[structopt(long = "cookie", number_of_values = 1)]
+    cookies: Vec<String>,
+    /// Fankits to download instead of crawling the list pages: IDs, item
+    /// URLs, or ID ranges such as `100..=150`
+    ///
+    /// IDs and ID ranges refer to the first section.
+    #[structopt(name = "FANKIT")]
+    fankits: Vec<String>,
+}
+
+impl CliOpt {
+    /// Returns the retry policy.
+    fn retry_policy(&self) -> Result<RetryPolicy, Error> {
+        let statuses = self
+            .retry_status
+            .iter()
+            .map(|&code| reqwest::StatusCode::from_u16(code))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::Config(format!("Invalid retry status: {}", e)))?;
+        Ok(RetryPolicy::default()
+            .max_attempts(self.max_attempts)
+            .backoff(
+                Duration::from_millis(self.retry_backoff),
+                Duration::from_millis(self.retry_max_backoff),
+            )
+            .jitter(f64::from(self.retry_jitter) / 100.0)
+            .retryable_statuses(statuses)
+            .retry_network_errors(!self.no_retry_network))
+    }
+
+    /// Returns the rate limiter.
+    fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(
+            Rate::new(Duration::from_millis(self.delay), 1),
+            Rate::new(Duration::from_millis(self.image_delay), self.jobs as u32),
+        )
+    }
+
+    /// Returns the run configuration.
+    fn run_config(&self) -> Result<RunConfig, Error> {
+        let config = RunConfig::new(&self.base_url, &self.sections)?;
+        let targets = self.targets(config.sites())?;
+        let config = config
+            .targets(targets)
+            .full(self.full)
+            .dry_run(self.dry_run)
+            .output(self.output)
+            .report(self.report.clone())
+            .known_pages_to_stop(self.known_pages_to_stop)
+            .download(self.download_config()?);
+        Ok(match &self.dest {
+            Some(dest) => config.dest_dir(dest.clone()),
+            None => config,
+        })
+    }
+
+    /// Returns the fankits specified directly.
+    fn targets(&self, sites: &[Site]) -> Result<Vec<FankitTarget>, Error> {
+        self.fankits
+            .iter()
+            .map(|fankit| {
+                FankitTarget::parse(fankit, sites)
+                    .map_err(|e| Error::Config(format!("Invalid fankit {:?}: {}", fankit, e)))
+            })
+            .collect()
+    }
+
+    /// Returns the downloader configuration.
+    fn download_config(&self) -> Result<DownloadConfig, Error> {
+        let ids = |ids: &String| {
+            parse_id_range(ids)
+                .map_err(|e| Error::Config(format!("Invalid fankit IDs {:?}: {}", ids, e)))
+        };
+        let mut filter = FankitFilter::default();
+        for ty in &self.include_types {
+            filter = filter.include_type(ty);
+        }
+        for ty in &self.exclude_types {
+            filter = filter.exclude_type(ty);
+        }
+        for pattern in &self.include_titles {
+            filter = filter.include_title(TitlePattern::glob(pattern)?);
+        }
+        for pattern in &self.exclude_titles {
+            filter = filter.exclude_title(TitlePattern::glob(pattern)?);
+        }
+        for pattern in &self.include_title_regexes {
+            filter = filter.include_title(TitlePattern::regex(pattern)?);
+        }
+        for pattern in &self.exclude_title_regexes {
+            filter = filter.exclude_title(TitlePattern::regex(pattern)?);
+        }
+        for range in &self.include_ids {
+            filter = filter.include_ids(ids(range)?);
+        }
+        for range in &self.exclude_ids {
+            filter = filter.exclude_ids(ids(range)?);
+        }
+
+        Ok(DownloadConfig::default()
+            .jobs(self.jobs)
+            .filter(filter)
+            .dir_template(NameTemplate::item_dir(&self.dir_template)?)
+            .file_template(NameTemplate::image_file(&self.file_template)?)
+            .parent_segments(self.parent_segments)
+            .asset_kinds(self.asset_kinds.iter().copied()))
+    }
+
+    /// Returns the HTTP client configuration.
+    fn http_config(&self) -> Result<HttpConfig, Error> {
+        let timeout = |millis| Some(Duration::from_millis(millis)).filter(|_| millis != 0);
+        let mut config = HttpConfig::default()
+            .connect_timeout(timeout(self.connect_timeout))
+            .read_timeout(timeout(self.read_timeout))
+            .proxy(self.proxy.clone())
+            .user_agent(self.user_agent.clone());
+        for header in &self.headers {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                Error::Config(format!(
+                    "Invalid header {:?}: expected `Name: value`",
+                    header
+                ))
+            })?;
+            config = config.header(name.trim(), value.trim());
+        }
+        for cookie in &self.cookies {
+            config = config.cookie(&self.base_url, cookie);
+        }
+
+        Ok(config)
+    }
 }
 
 /// Initialize logger.
@@ -40,141 +296,43 @@ fn init_logger() {
         .init();
 }
 
-fn write_to_buffered_file<F>(out_path: &Path, f: F) -> io::Result<()>
-where
-    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
-{
-    // Create the file and the writer.
-    let file = match fs::File::create(out_path) {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("Failed to create a file {}: {}", out_path.display(), e);
-            return Err(e);
-        }
-    };
-    let mut writer = BufWriter::new(file);
-
-    // Do the job.
-    f(&mut writer)?;
-
-    // Flush the writer.
-    if let Err(e) = writer.flush() {
-        log::warn!("Failed to flush the buffer: {}", e);
-    }
-    let file = match writer.into_inner() {
-        Ok(v) => v,
-        Err(e) => {
-            log::error!("Failed to finalize the buffer: {}", e);
-            return Err(e.into());
-        }
-    };
-
-    // Sync the file.
-    if let Err(e) = file.sync_all() {
-        log::error!(
-            "Failed to sync the output file {}: {}",
-            out_path.display(),
-            e
-        );
-        return Err(e);
-    }
-
-    Ok(())
-}
-
-fn download_fankits(
-    dest_dir: &Path,
-    fankits: &HashSet<FankitId>,
-    downloaded_items: &HashSet<FankitId>,
-    crawl_delay: Duration,
-) -> Result<(), BoxedError> {
-    log::debug!("fankits = {:?}", fankits);
-
-    for fankit in fankits {
-        if downloaded_items.contains(fankit) {
-            // Already downloaded.
-            log::info!("Skipping fankit {:?}", fankit);
-            continue;
-        }
-        let info = fankit.load()?;
-
-        let item_name = info.item_name();
-        log::debug!("info = {:?}", info);
-        log::info!("Downloading images in item {:?}", item_name);
-
-        let item_dir = dest_dir.join(&item_name);
-        if let Err(e) = fs::create_dir(&item_dir) {
-            log::error!("Failed to create item dir {:?}: {}", item_dir.display(), e);
-        }
-        for image_url in info.image_urls() {
-            log::trace!("Downloading image {:?}", image_url);
-            let image_filename = {
-                let last_slash = image_url
-                    .rfind('/')
-                    .expect("URL must have slash characters");
-                &image_url[(last_slash + 1)..]
-            };
-            let mut resp = match client()?.get(image_url).send() {
-                Ok(v) => v,
-                Err(e) => {
-                    log::error!("Failed to download image {:?}: {}", image_url, e);
-                    continue;
-                }
-            };
-            let image_path = item_dir.join(image_filename);
-            let write_result = write_to_buffered_file(&image_path, |writer| {
-                resp.copy_to(writer)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                Ok(())
-            });
-            if let Err(e) = write_result {
-                log::error!("Failed to write image file {}: {}", image_path.display(), e);
-                continue;
-            }
-        }
+/// Exit status for the runs with items or images failed to download.
+const EXIT_INCOMPLETE: u8 = 8;
 
-        log::debug!("Sleeping for {:?}", crawl_delay);
-        std::thread::sleep(crawl_delay);
+/// Returns the exit status for the error.
+fn exit_code(e: &Error) -> u8 {
+    match e {
+        Error::Config(_) => 2,
+        Error::Network { .. } => 3,
+        Error::HttpStatus(_) => 4,
+        Error::LayoutChanged { .. } => 5,
+        Error::Io { .. } => 6,
+        Error::Manifest { .. } => 7,
+        _ => 1,
     }
-
-    Ok(())
 }
 
-fn main() -> Result<(), BoxedError> {
+fn main() -> ExitCode {
     init_logger();
 
     let opt = CliOpt::from_args();
 
-    let crawl_delay = Duration::from_millis(opt.delay);
-    let dest_dir = match &opt.dest {
-        Some(dest) => Cow::Borrowed(dest.as_path()),
-        None => Cow::Owned(std::env::current_dir()?),
-    };
-    log::debug!("destination directory: {}", dest_dir.display());
-
-    let dir_items = fs::read_dir(&dest_dir)?
-        .map(|ent_res| ent_res.map(|entry| entry.file_name().to_string_lossy().into_owned()))
-        .collect::<Result<Vec<_>, _>>()?;
-
-    let downloaded_items = dir_items
-        .iter()
-        .filter_map(|name| name.find('-').map(|hyphen_pos| &name[..hyphen_pos]))
-        .flat_map(|num_str| num_str.parse::<usize>())
-        .map(FankitId::new)
-        .collect::<HashSet<_>>();
-    match get_fankits_if_new_fankit_found(downloaded_items.iter().copied(), crawl_delay)? {
-        Some(fankits) => download_fankits(&dest_dir, &fankits, &downloaded_items, crawl_delay)?,
-        None => log::info!("There seems to be no new fankits"),
+    match run_cli(&opt) {
+        Ok(summary) if summary.is_success() => ExitCode::SUCCESS,
+        Ok(_) => {
+            log::error!("Some items or images failed to download");
+            ExitCode::from(EXIT_INCOMPLETE)
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::from(exit_code(&e))
+        }
     }
-
-    Ok(())
 }
 
-/// Creates an HTTP client.
-fn client() -> reqwest::Result<reqwest::blocking::Client> {
-    static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
-
-    reqwest::blocking::Client::builder()
-        .user_agent(APP_USER_AGENT)
-        .build()
+/// Runs the downloader with the command line options.
+fn run_cli(opt: &CliOpt) -> Result<Summary, Error> {
+    let config = opt.run_config()?;
+    let client = HttpClient::new(&opt.http_config()?, opt.retry_policy()?, opt.rate_limiter())?;
+    run(&client, &config)
 }
@@ -0,0 +1,558 @@
+//! Download manifest.
+//!
+//! The manifest is a JSON file in the destination directory which records
+//! every fankit item downloaded so far, and the state of each image in it.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::Sha256Writer,
+    error::Error,
+    fankit::{FankitId, FankitInfo, Site, DEFAULT_SECTION},
+    file::{is_temp_file, write_atomically},
+};
+
+/// Manifest file name in the destination directory.
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Current manifest format version.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Download manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Format version.
+    version: u32,
+    /// Item records.
+    items: BTreeMap<FankitId, ItemRecord>,
+    /// IDs of the fankits rejected by the filter and not downloaded.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    filtered: BTreeSet<FankitId>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            items: BTreeMap::new(),
+            filtered: BTreeSet::new(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Loads the manifest from the given path.
+    ///
+    /// Returns an empty manifest if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("Manifest {} not found, starting afresh", path.display());
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(Error::io(path, e)),
+        };
+        let manifest: Self = serde_json::from_reader(BufReader::new(file)).map_err(|e| {
+            if e.is_io() {
+                Error::io(path, e.into())
+            } else {
+                Error::manifest(path, e)
+            }
+        })?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(Error::manifest(
+                path,
+                format!("Unsupported manifest version {}", manifest.version),
+            ));
+        }
+
+        Ok(manifest)
+    }
+
+    /// Creates a manifest from the item directories written by the versions
+    /// without manifests.
+    ///
+    /// Directories named `{id}-{type}-{title}` directly under `dest_dir` are
+    /// recorded as the items in the default section, with the files in them
+    /// as the images.
+    /// The source URLs of the images are unknown, and left empty.
+    pub fn import_legacy(dest_dir: &Path) -> Result<Self, Error> {
+        let mut manifest = Self::default();
+        let entries = match fs::read_dir(dest_dir) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(manifest),
+            Err(e) => return Err(Error::io(dest_dir, e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::io(dest_dir, e))?;
+            let item_dir = entry.path();
+            if !entry
+                .file_type()
+                .map_err(|e| Error::io(&item_dir, e))?
+                .is_dir()
+            {
+                continue;
+            }
+            let dir = match entry.file_name().into_string() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let (id, rest) = match dir.split_once('-') {
+                Some((id, rest)) => (id, rest),
+                None => continue,
+            };
+            let id = match id.parse::<usize>() {
+                Ok(v) => FankitId::new(v),
+                Err(_) => continue,
+            };
+            // Types have no hyphens, but titles may have.
+            let (ty, title) = rest.split_once('-').unwrap_or((rest, ""));
+            let mut images = Vec::new();
+            for entry in fs::read_dir(&item_dir).map_err(|e| Error::io(&item_dir, e))? {
+                let entry = entry.map_err(|e| Error::io(&item_dir, e))?;
+                let path = entry.path();
+                if !entry
+                    .file_type()
+                    .map_err(|e| Error::io(&path, e))?
+                    .is_file()
+                    || is_temp_file(&path)
+                {
+                    continue;
+                }
+                let filename = match entry.file_name().into_string() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                images.push(ImageRecord::import(&path, filename)?);
+            }
+            images.sort_by(|a, b| a.filename.cmp(&b.filename));
+            log::info!(
+                "Importing fankit {:?} with {} images from {}",
+                id,
+                images.len(),
+                item_dir.display()
+            );
+            manifest.insert(ItemRecord {
+                id,
+                section: default_section(),
+                ty: ty.to_owned(),
+                title: title.to_owned(),
+                dir,
+                expected_images: None,
+                images,
+            });
+        }
+
+        Ok(manifest)
+    }
+
+    /// Saves the manifest to the given path atomically.
+    ///
+    /// The manifest is written to a temporary file first, and then renamed to
+    /// the given path, so that the old manifest is kept intact on failure.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        write_atomically(path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, self)?;
+            writer.write_all(b"\n")
+        })
+        .map_err(|e| Error::io(path, e))
+    }
+
+    /// Returns the record for the given item.
+    pub fn item(&self, id: FankitId) -> Option<&ItemRecord> {
+        self.items.get(&id)
+    }
+
+    /// Returns an iterator of IDs of the recorded items.
+    pub fn items(&self) -> impl Iterator<Item = FankitId> + '_ {
+        self.items.keys().copied()
+    }
+
+    /// Returns an iterator of IDs of the items which are not completely
+    /// downloaded or whose files are missing or broken.
+    pub fn items_to_repair<'a>(
+        &'a self,
+        dest_dir: &'a Path,
+    ) -> impl Iterator<Item = FankitId> + 'a {
+        self.items
+            .values()
+            .filter(move |item| !item.is_intact(dest_dir))
+            .map(|item| item.id)
+    }
+
+    /// Returns an iterator of IDs of the fankits rejected by the filter and
+    /// not downloaded.
+    ///
+    /// They are known to the crawler, so that list pages with only
+    /// downloaded and filtered fankits do not look new.
+    pub fn filtered(&self) -> impl Iterator<Item = FankitId> + '_ {
+        self.filtered.iter().copied()
+    }
+
+    /// Inserts or replaces the record for an item.
+    pub fn insert(&mut self, record: ItemRecord) {
+        self.filtered.remove(&record.id);
+        self.items.insert(record.id, record);
+    }
+
+    /// Records the fankit as rejected by the filter, unless it is already
+    /// downloaded.
+    ///
+    /// Returns whether the manifest is changed.
+    pub fn insert_filtered(&mut self, id: FankitId) -> bool {
+        !self.items.contains_key(&id) && self.filtered.insert(id)
+    }
+}
+
+/// Record of a fankit item.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemRecord {
+    /// ID.
+    id: FankitId,
+    /// Section of the site the item belongs to.
+    #[serde(default = "default_section")]
+    section: String,
+    /// Fankit type.
+    ty: String,
+    /// Title.
+    title: String,
+    /// Item directory name, relative to the destination directory.
+    dir: String,
+    /// Number of images selected for download when the record is written.
+    ///
+    /// `None` for the records written before this is recorded, and for the
+    /// imported records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expected_images: Option<usize>,
+    /// Images.
+    images: Vec<ImageRecord>,
+}
+
+impl ItemRecord {
+    /// Creates a new record for the given item in the site, without any
+    /// images.
+    ///
+    /// `dir` is the item directory name relative to the destination
+    /// directory, with `/` as the separator.
+    pub fn new(info: &FankitInfo, site: &Site, dir: String) -> Self {
+        Self {
+            id: info.id(),
+            section: site.section().to_owned(),
+            ty: info.ty().to_owned(),
+            title: info.title().to_owned(),
+            dir,
+            expected_images: None,
+            images: Vec::new(),
+        }
+    }
+
+    /// Creates a new record for the given item in the site, keeping the
+    /// directory of the previous record.
+    pub fn renew(info: &FankitInfo, site: &Site, previous: &Self) -> Self {
+        Self::new(info, site, previous.dir.clone())
+    }
+
+    /// Returns the section of the site the item belongs to.
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    /// Returns the item directory name.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Returns the record of the image with the given URL.
+    pub fn image(&self, url: &str) -> Option<&ImageRecord> {
+        self.images.iter().find(|image| image.url == url)
+    }
+
+    /// Returns the record of the image imported without the URL, with the
+    /// given file name.
+    pub fn imported_image(&self, filename: &str) -> Option<&ImageRecord> {
+        self.images
+            .iter()
+            .find(|image| image.url.is_empty() && image.filename == filename)
+    }
+
+    /// Returns the image records.
+    pub fn images(&self) -> &[ImageRecord] {
+        &self.images
+    }
+
+    /// Adds an image record.
+    pub fn push_image(&mut self, image: ImageRecord) {
+        self.images.push(image);
+    }
+
+    /// Records the number of images selected for download, so that an item
+    /// with no images to download is distinguished from a broken record.
+    pub fn expect_images(&mut self, count: usize) {
+        self.expected_images = Some(count);
+    }
+
+    /// Returns whether all images in the item are downloaded and present on
+    /// the disk with the recorded sizes.
+    ///
+    /// The number of images should match the number expected when the record
+    /// is written.
+    /// Records without the expected number are not intact if they have no
+    /// images, since the item page may have failed to list them.
+    pub fn is_intact(&self, dest_dir: &Path) -> bool {
+        let expected = match self.expected_images {
+            Some(expected) => expected,
+            None if self.images.is_empty() => {
+                log::warn!("Fankit {:?} has no images recorded", self.id);
+                return false;
+            }
+            None => self.images.len(),
+        };
+        if self.images.len() != expected {
+            log::warn!(
+                "Fankit {:?} has {} images recorded, expected {}",
+                self.id,
+                self.images.len(),
+                expected
+            );
+            return false;
+        }
+        let item_dir = dest_dir.join(&self.dir);
+        self.images.iter().all(|image| image.is_intact(&item_dir))
+    }
+}
+
+/// Returns the section of the items recorded before sections are supported.
+fn default_section() -> String {
+    DEFAULT_SECTION.to_owned()
+}
+
+/// Record of an image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageRecord {
+    /// Source URL.
+    url: String,
+    /// File name in the item directory.
+    filename: String,
+    /// File size in bytes.
+    size: Option<u64>,
+    /// Hex-encoded SHA-256 checksum.
+    sha256: Option<String>,
+    /// `Last-Modified` header of the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_modified: Option<String>,
+    /// `ETag` header of the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    /// Time the download is completed, in HTTP date format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    downloaded_at: Option<String>,
+}
+
+impl ImageRecord {
+    /// Creates a record for a completely downloaded image.
+    ///
+    /// The download time is set to the current time.
+    pub fn complete(url: &str, filename: &str, size: u64, sha256: String) -> Self {
+        Self {
+            url: url.to_owned(),
+            filename: filename.to_owned(),
+            size: Some(size),
+            sha256: Some(sha256),
+            last_modified: None,
+            etag: None,
+            downloaded_at: Some(httpdate::fmt_http_date(SystemTime::now())),
+        }
+    }
+
+    /// Creates a record for an image not downloaded yet.
+    pub fn incomplete(url: &str, filename: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            filename: filename.to_owned(),
+            size: None,
+            sha256: None,
+            last_modified: None,
+            etag: None,
+            downloaded_at: None,
+        }
+    }
+
+    /// Creates a record for an existing image file, whose source URL is
+    /// unknown.
+    ///
+    /// The download time is set to the modification time of the file.
+    fn import(path: &Path, filename: String) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let modified = file.metadata().and_then(|meta| meta.modified()).ok();
+        let mut writer = Sha256Writer::new(io::sink());
+        writer
+            .feed(BufReader::new(file))
+            .map_err(|e| Error::io(path, e))?;
+        let (_, size, sha256) = writer.finish();
+        Ok(Self {
+            url: String::new(),
+            filename,
+            size: Some(size),
+            sha256: Some(sha256),
+            last_modified: None,
+            etag: None,
+            downloaded_at: modified.map(httpdate::fmt_http_date),
+        })
+    }
+
+    /// Sets the source URL, for images imported without it.
+    pub(crate) fn with_url(self, url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            ..self
+        }
+    }
+
+    /// Sets the `Last-Modified` and `ETag` headers of the response.
+    pub fn with_validators(self, last_modified: Option<String>, etag: Option<String>) -> Self {
+        Self {
+            last_modified,
+            etag,
+            ..self
+        }
+    }
+
+    /// Returns the source URL.
+    ///
+    /// The URL is empty if unknown, for images imported by
+    /// [`Manifest::import_legacy`].
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the file name in the item directory.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns the size in bytes, if downloaded.
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Returns whether the image is downloaded.
+    pub fn is_complete(&self) -> bool {
+        self.sha256.is_some()
+    }
+
+    /// Returns the path of the image file.
+    pub fn path(&self, item_dir: &Path) -> PathBuf {
+        item_dir.join(&self.filename)
+    }
+
+    /// Returns whether the image is downloaded and present on the disk with
+    /// the recorded size.
+    ///
+    /// Missing, empty, and truncated files are considered broken.
+    pub fn is_intact(&self, item_dir: &Path) -> bool {
+        let size = match self.size {
+            Some(size) if self.is_complete() && size > 0 => size,
+            _ => return false,
+        };
+        let path = self.path(item_dir);
+        match fs::metadata(&path) {
+            Ok(meta) if meta.is_file() && meta.len() == size => true,
+            Ok(meta) => {
+                log::warn!(
+                    "Image {} is broken: expected {} bytes, found {} bytes",
+                    path.display(),
+                    size,
+                    meta.len()
+                );
+                false
+            }
+            Err(e) => {
+                log::warn!("Image {} is missing: {}", path.display(), e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut manifest = Manifest::default();
+        manifest.insert(ItemRecord {
+            id: FankitId::new(42),
+            section: DEFAULT_SECTION.to_owned(),
+            ty: "Wallpaper".to_owned(),
+            title: "Title".to_owned(),
+            dir: "42-Wallpaper-Title".to_owned(),
+            expected_images: Some(2),
+            images: vec![
+                ImageRecord::complete("https://example.com/a.png", "a.png", 3, "abc".to_owned())
+                    .with_validators(
+                        Some("Fri, 10 Jan 2020 00:00:00 GMT".to_owned()),
+                        Some("\"abc\"".to_owned()),
+                    ),
+                ImageRecord::incomplete("https://example.com/b.png", "b.png"),
+            ],
+        });
+
+        let json = serde_json::to_string(&manifest).expect("should be serializable");
+        let loaded: Manifest = serde_json::from_str(&json).expect("should be deserializable");
+        assert_eq!(loaded, manifest);
+    }
+
+    #[test]
+    fn missing_images_need_repair() {
+        let mut manifest = Manifest::default();
+        manifest.insert(ItemRecord {
+            id: FankitId::new(1),
+            section: DEFAULT_SECTION.to_owned(),
+            ty: "Wallpaper".to_owned(),
+            title: "Empty".to_owned(),
+            dir: "1-Wallpaper-Empty".to_owned(),
+            expected_images: None,
+            images: Vec::new(),
+        });
+        manifest.insert(ItemRecord {
+            id: FankitId::new(2),
+            section: DEFAULT_SECTION.to_owned(),
+            ty: "Wallpaper".to_owned(),
+            title: "Missing".to_owned(),
+            dir: "2-Wallpaper-Missing".to_owned(),
+            expected_images: Some(1),
+            images: vec![ImageRecord::complete(
+                "https://example.com/a.png",
+                "a.png",
+                3,
+                "abc".to_owned(),
+            )],
+        });
+        // No images are selected for download.
+        manifest.insert(ItemRecord {
+            id: FankitId::new(3),
+            section: DEFAULT_SECTION.to_owned(),
+            ty: "Wallpaper".to_owned(),
+            title: "Nothing".to_owned(),
+            dir: "3-Wallpaper-Nothing".to_owned(),
+            expected_images: Some(0),
+            images: Vec::new(),
+        });
+
+        let dest_dir = Path::new("/nonexistent-priconne-fankit-dl-dest");
+        assert_eq!(
+            manifest.items_to_repair(dest_dir).collect::<Vec<_>>(),
+            vec![FankitId::new(1), FankitId::new(2)]
+        );
+    }
+}
@@ -0,0 +1,521 @@
+//! Names of item directories and image files.
+//!
+//! Names are rendered from templates such as `{id:05}-{type}/{title}`, and
+//! each path component is sanitized so that it is safe on common
+//! filesystems.
+
+use std::{collections::HashSet, fmt::Write as _};
+
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::Error,
+    fankit::{Asset, FankitInfo, Site},
+};
+
+/// Default template of item directories.
+pub const DEFAULT_DIR_TEMPLATE: &str = "{id}-{type}-{title}";
+
+/// Default template of image files.
+pub const DEFAULT_FILE_TEMPLATE: &str = "{filename}";
+
+/// Maximum length of a path component in bytes.
+///
+/// This is less than 255 bytes, the limit of most filesystems, to leave room
+/// for the suffixes of the temporary files.
+const MAX_COMPONENT_BYTES: usize = 200;
+
+/// Maximum length of an extension kept on truncation, in bytes.
+const MAX_EXTENSION_BYTES: usize = 16;
+
+/// Names reserved on Windows, regardless of the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Field in a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// Fankit ID.
+    Id,
+    /// Section of the site.
+    Section,
+    /// Fankit type.
+    Type,
+    /// Title.
+    Title,
+    /// File name of the image.
+    Filename,
+    /// File name of the image without the extension.
+    Stem,
+    /// Extension of the image, without the dot.
+    Ext,
+    /// Position of the image in the page.
+    Position,
+    /// Label text near the image.
+    Label,
+}
+
+impl Field {
+    /// Parses the field name.
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "id" => Self::Id,
+            "section" => Self::Section,
+            "type" => Self::Type,
+            "title" => Self::Title,
+            "filename" => Self::Filename,
+            "stem" => Self::Stem,
+            "ext" => Self::Ext,
+            "position" => Self::Position,
+            "label" => Self::Label,
+            _ => return None,
+        })
+    }
+
+    /// Returns whether the field is only available for images.
+    fn is_image_field(self) -> bool {
+        matches!(
+            self,
+            Self::Filename | Self::Stem | Self::Ext | Self::Position | Self::Label
+        )
+    }
+}
+
+/// Piece of a path component in a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    /// Literal text.
+    Literal(String),
+    /// Field, with the width to zero-pad.
+    Field(Field, usize),
+}
+
+/// Template of names.
+///
+/// `{id}`, `{section}`, `{type}`, and `{title}` are replaced with the
+/// attributes of the fankit, and `{filename}`, `{stem}`, and `{ext}` are
+/// replaced with the file name of the image in the image file templates.
+/// `{position}` and `{label}` are replaced with the position of the image in
+/// the page (starting from 1) and the label text near it (empty if not
+/// found).
+/// `{id:05}` and `{position:02}` pad the numbers with zeros to the width, and
+/// `{{` and `}}` are literal braces.
+///
+/// `/` separates directories. Each path component is sanitized after the
+/// fields are replaced, so the fields never introduce extra directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    /// Path components.
+    components: Vec<Vec<Piece>>,
+}
+
+impl NameTemplate {
+    /// Parses a template of item directories.
+    ///
+    /// The template must have `{id}`, so that the directories of different
+    /// items never collide.
+    pub fn item_dir(template: &str) -> Result<Self, Error> {
+        let parsed = Self::parse(template)?;
+        let has_image_field = parsed.fields().any(|(field, _)| field.is_image_field());
+        if has_image_field {
+            return Err(invalid(
+                template,
+                "image fields are not available for directories",
+            ));
+        }
+        if !parsed.fields().any(|(field, _)| field == Field::Id) {
+            return Err(invalid(template, "directories must have `{id}`"));
+        }
+        Ok(parsed)
+    }
+
+    /// Parses a template of image files, relative to the item directory.
+    pub fn image_file(template: &str) -> Result<Self, Error> {
+        Self::parse(template)
+    }
+
+    /// Parses a template.
+    fn parse(template: &str) -> Result<Self, Error> {
+        let mut components = vec![Vec::new()];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(invalid(template, "unclosed `{`")),
+                        }
+                    }
+                    let (name, format) = match spec.split_once(':') {
+                        Some((name, format)) => (name, Some(format)),
+                        None => (spec.as_str(), None),
+                    };
+                    let field = Field::parse(name)
+                        .ok_or_else(|| invalid(template, &format!("unknown field {:?}", name)))?;
+                    let width = match format {
+                        None => 0,
+                        Some(format)
+                            if matches!(field, Field::Id | Field::Position)
+                                && format.starts_with('0') =>
+                        {
+                            format.parse().map_err(|_| {
+                                invalid(template, &format!("invalid width {:?}", format))
+                            })?
+                        }
+                        Some(format) => {
+                            return Err(invalid(
+                                template,
+                                &format!("unsupported format {:?} for {:?}", format, name),
+                            ))
+                        }
+                    };
+                    let pieces = components.last_mut().expect("should never be empty");
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Piece::Field(field, width));
+                }
+                '}' => return Err(invalid(template, "unmatched `}`")),
+                '/' => {
+                    let pieces = components.last_mut().expect("should never be empty");
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(Vec::new());
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            components
+                .last_mut()
+                .expect("should never be empty")
+                .push(Piece::Literal(literal));
+        }
+        if components.iter().any(Vec::is_empty) {
+            return Err(invalid(template, "empty path component"));
+        }
+
+        Ok(Self { components })
+    }
+
+    /// Returns an iterator of the fields in the template.
+    fn fields(&self) -> impl Iterator<Item = (Field, usize)> + '_ {
+        self.components
+            .iter()
+            .flatten()
+            .filter_map(|piece| match piece {
+                Piece::Field(field, width) => Some((*field, *width)),
+                Piece::Literal(_) => None,
+            })
+    }
+
+    /// Renders the relative path with `/` as the separator.
+    ///
+    /// `image` is the image with its file name in the URL, used for the image
+    /// fields.
+    pub fn render(&self, info: &FankitInfo, site: &Site, image: Option<(&Asset, &str)>) -> String {
+        let filename = image.map_or("", |(_, filename)| filename);
+        let (stem, ext) = split_extension(filename);
+        let last = self.components.len() - 1;
+        let mut path = String::new();
+        for (index, pieces) in self.components.iter().enumerate() {
+            let mut component = String::new();
+            for piece in pieces {
+                match piece {
+                    Piece::Literal(s) => component.push_str(s),
+                    Piece::Field(Field::Id, width) => {
+                        write!(component, "{:0width$}", info.id().to_usize(), width = width)
+                            .expect("writing to a string should never fail");
+                    }
+                    Piece::Field(Field::Section, _) => component.push_str(site.section()),
+                    Piece::Field(Field::Type, _) => component.push_str(info.ty()),
+                    Piece::Field(Field::Title, _) => component.push_str(info.title()),
+                    Piece::Field(Field::Filename, _) => component.push_str(filename),
+                    Piece::Field(Field::Stem, _) => component.push_str(stem),
+                    Piece::Field(Field::Ext, _) => component.push_str(ext.unwrap_or_default()),
+                    Piece::Field(Field::Position, width) => {
+                        let position = image.map_or(0, |(asset, _)| asset.position());
+                        write!(component, "{:0width$}", position, width = width)
+                            .expect("writing to a string should never fail");
+                    }
+                    Piece::Field(Field::Label, _) => component.push_str(
+                        image
+                            .and_then(|(asset, _)| asset.label())
+                            .unwrap_or_default(),
+                    ),
+                }
+            }
+            if index != 0 {
+                path.push('/');
+            }
+            path.push_str(&sanitize_component(&component, index == last));
+        }
+        path
+    }
+}
+
+/// Creates a template error.
+fn invalid(template: &str, reason: &str) -> Error {
+    Error::Config(format!("Invalid name template {:?}: {}", template, reason))
+}
+
+/// Splits the file name into the stem and the extension.
+fn split_extension(filename: &str) -> (&str, Option<&str>) {
+    match filename.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.is_empty() => (stem, Some(ext)),
+        _ => (filename, None),
+    }
+}
+
+/// Returns the file name of the image URL.
+///
+/// The file name is the last segment of the percent-decoded URL path, without
+/// the query and the fragment. `parent_segments` parent path segments are
+/// kept before the file name, separated by `/`.
+pub fn url_filename(url: &str, parent_segments: usize) -> String {
+    let url = url.split(['?', '#']).next().unwrap_or_default();
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map_or("", |(_, path)| path),
+        None => url,
+    };
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let start = segments.len().saturating_sub(parent_segments + 1);
+    segments[start..]
+        .iter()
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Set of file names in a directory, which makes new names unique.
+///
+/// Names are compared case-insensitively, since some filesystems are case
+/// insensitive.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UniqueNames {
+    /// Names in lower case.
+    names: HashSet<String>,
+}
+
+impl UniqueNames {
+    /// Marks the name as used.
+    pub(crate) fn reserve(&mut self, name: &str) {
+        self.names.insert(name.to_lowercase());
+    }
+
+    /// Returns the name, with `-2`, `-3`, ... inserted before the extension
+    /// if it is already used, and marks it as used.
+    pub(crate) fn unique(&mut self, name: String) -> String {
+        if self.names.insert(name.to_lowercase()) {
+            return name;
+        }
+        let (dir, file) = match name.rfind('/') {
+            Some(slash) => name.split_at(slash + 1),
+            None => ("", name.as_str()),
+        };
+        let (stem, ext) = split_extension(file);
+        (2..)
+            .map(|n| match ext {
+                Some(ext) => format!("{}{}-{}.{}", dir, stem, n, ext),
+                None => format!("{}{}-{}", dir, stem, n),
+            })
+            .find(|candidate| self.names.insert(candidate.to_lowercase()))
+            .expect("should be found before overflow")
+    }
+}
+
+/// Makes the path component safe on common filesystems.
+///
+/// Path separators, control characters, and characters not allowed on
+/// Windows are replaced with `_`, trailing dots and spaces are removed, and
+/// names reserved on Windows get `_` appended.
+/// Components longer than the limit are truncated, and a hash of the whole
+/// component is appended to keep them distinct.
+/// The extension is kept on truncation if `keep_extension` is true.
+pub fn sanitize_component(component: &str, keep_extension: bool) -> String {
+    let mut sanitized = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let trimmed_len = sanitized.trim_end_matches(['.', ' ']).len();
+    sanitized.truncate(trimmed_len);
+    let sanitized = sanitized.trim_start_matches(' ');
+    if sanitized.is_empty() {
+        return "_".to_owned();
+    }
+    let mut sanitized = sanitized.to_owned();
+
+    let base = sanitized.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(base.trim_end()))
+    {
+        sanitized.insert(base.len(), '_');
+    }
+
+    if sanitized.len() <= MAX_COMPONENT_BYTES {
+        return sanitized;
+    }
+    let ext = if keep_extension {
+        split_extension(&sanitized)
+            .1
+            .filter(|ext| ext.len() < MAX_EXTENSION_BYTES)
+            .map(|ext| format!(".{}", ext))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let digest = Sha256::digest(component.as_bytes());
+    let hash = digest[..4].iter().fold(String::new(), |mut hash, byte| {
+        write!(hash, "{:02x}", byte).expect("writing to a string should never fail");
+        hash
+    });
+    let mut end = MAX_COMPONENT_BYTES - ext.len() - hash.len() - 1;
+    while !sanitized.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}~{}{}", &sanitized[..end], hash, ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fankit::FankitId;
+
+    #[test]
+    fn render() {
+        let site = Site::default();
+        let info = FankitInfo::fixture(42, "壁紙", "ペコリーヌ");
+        let dir = |template| {
+            NameTemplate::item_dir(template)
+                .expect("should be valid")
+                .render(&info, &site, None)
+        };
+        assert_eq!(dir(DEFAULT_DIR_TEMPLATE), "42-壁紙-ペコリーヌ");
+        assert_eq!(dir("{id:05}-{type}/{title}"), "00042-壁紙/ペコリーヌ");
+        assert_eq!(dir("{section}/{{{id}}}"), "fankit02/{42}");
+
+        let html = r#"<html><body><div id="contents">
+            <p class="fankit-type">壁紙</p>
+            <h2 class="title">ペコリーヌ</h2>
+            <ul>
+                <li>PC <a href="pecorine_pc.png"></a></li>
+                <li><a href="pecorine_sp.png"></a></li>
+            </ul>
+            </div></body></html>"#;
+        let info = FankitInfo::parse_html(FankitId::new(42), &site, html).unwrap();
+        let file = |template, index: usize| {
+            let asset = &info.assets()[index];
+            NameTemplate::image_file(template)
+                .expect("should be valid")
+                .render(&info, &site, Some((asset, &url_filename(asset.url(), 0))))
+        };
+        assert_eq!(file(DEFAULT_FILE_TEMPLATE, 0), "pecorine_pc.png");
+        assert_eq!(file("{id}-{stem}.{ext}", 0), "42-pecorine_pc.png");
+        assert_eq!(file("{position:02}-{label}.{ext}", 0), "01-PC.png");
+        assert_eq!(file("{position:02}-{label}.{ext}", 1), "02-.png");
+    }
+
+    #[test]
+    fn fields_are_sanitized() {
+        let site = Site::default();
+        let info = FankitInfo::fixture(42, "壁紙", "A/B: C?");
+        let template = NameTemplate::item_dir("{type}/{title}-{id}").expect("should be valid");
+        assert_eq!(template.render(&info, &site, None), "壁紙/A_B_ C_-42");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in &[
+            "{unknown}",
+            "{id",
+            "id}",
+            "{title:05}",
+            "{id:5}",
+            "{id}//{title}",
+            "/{id}",
+            "{id}/",
+        ] {
+            assert!(
+                matches!(NameTemplate::image_file(template), Err(Error::Config(_))),
+                "{:?} should be invalid",
+                template
+            );
+        }
+        assert!(NameTemplate::item_dir("{id}-{filename}").is_err());
+        assert!(NameTemplate::item_dir("{position}").is_err());
+        assert!(NameTemplate::item_dir("{type}/{title}").is_err());
+        assert!(NameTemplate::image_file("{label:02}").is_err());
+    }
+
+    #[test]
+    fn filenames_from_urls() {
+        let url = "https://example.com/wp-content/uploads/2020/01/%E5%A3%81%E7%B4%99.png?v=1#top";
+        assert_eq!(url_filename(url, 0), "壁紙.png");
+        assert_eq!(url_filename(url, 2), "2020/01/壁紙.png");
+        assert_eq!(url_filename(url, 10), "wp-content/uploads/2020/01/壁紙.png");
+        assert_eq!(url_filename("https://example.com/a/b.png/", 0), "b.png");
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut names = UniqueNames::default();
+        names.reserve("fankit.json");
+        assert_eq!(names.unique("01.png".to_owned()), "01.png");
+        assert_eq!(names.unique("01.PNG".to_owned()), "01-2.PNG");
+        assert_eq!(names.unique("01.png".to_owned()), "01-3.png");
+        assert_eq!(names.unique("fankit.json".to_owned()), "fankit-2.json");
+        assert_eq!(names.unique("a.b/c".to_owned()), "a.b/c");
+        assert_eq!(names.unique("a.b/c".to_owned()), "a.b/c-2");
+    }
+
+    #[test]
+    fn sanitize() {
+        assert_eq!(sanitize_component("a/b\\c\u{0}d", false), "a_b_c_d");
+        assert_eq!(sanitize_component("..", false), "_");
+        assert_eq!(sanitize_component("", false), "_");
+        assert_eq!(sanitize_component("title. ", false), "title");
+        assert_eq!(sanitize_component("con", false), "con_");
+        assert_eq!(sanitize_component("NUL.png", true), "NUL_.png");
+        assert_eq!(sanitize_component("console", false), "console");
+    }
+
+    #[test]
+    fn long_components_are_truncated() {
+        let long = "あ".repeat(100);
+        let truncated = sanitize_component(&long, false);
+        assert!(truncated.len() <= MAX_COMPONENT_BYTES);
+        assert!(truncated.starts_with("あああ"));
+        // Stable, and distinct for different inputs.
+        assert_eq!(sanitize_component(&long, false), truncated);
+        assert_ne!(sanitize_component(&format!("{}い", long), false), truncated);
+
+        let file = sanitize_component(&format!("{}.png", long), true);
+        assert!(file.len() <= MAX_COMPONENT_BYTES);
+        assert!(file.ends_with(".png"));
+    }
+}
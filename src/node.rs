@@ -4,56 +4,69 @@ use std::collections::HashSet;
 
 use html5ever::{parse_document, tree_builder::Attribute};
 use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use url::Url;
 
-use crate::client;
+use crate::{error::Error, http::HttpClient};
 
 pub use self::traverse::Traverse;
 
 mod traverse;
 
-/// Returns hrefs of anchors in the given URL.
-pub fn get_anchors(node: Handle) -> impl Iterator<Item = String> {
+/// Returns URLs of anchors in the given node, resolved against the base URL.
+pub fn get_anchors(node: Handle, base: &Url) -> impl Iterator<Item = Url> {
     // Get anchors.
     // Collect to `HashSet<_>` to deduplicate.
-    // allow(clippy::mutable_key_type): This is a hashset of tendrils, so allowing
-    // interior mutability here is safe.
-    #[allow(clippy::mutable_key_type)]
     let anchors = Traverse::new(node)
-        .filter_map(|node| match &node.data {
-            NodeData::Element { name, attrs, .. } => {
-                if &name.local == "a" {
-                    // Found an anchor.
-                    attrs
-                        .borrow()
-                        .iter()
-                        .find(|attr| &attr.name.local == "href")
-                        .map(|attr| attr.value.clone())
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        })
+        .filter(|node| node_is_element("a", node))
+        .filter_map(|node| node_attr("href", &node))
+        .filter_map(|href| resolve_url(base, &href))
         .collect::<HashSet<_>>();
 
-    anchors.into_iter().map(|href| href.to_string())
+    anchors.into_iter()
 }
 
-pub fn load_dom(url: &str) -> Result<RcDom, Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// Returns the base URL of the document at the page URL.
+///
+/// This is the `href` of the first `<base>` element resolved against the page
+/// URL if available, or the page URL itself.
+pub fn document_base(document: &Handle, page_url: &Url) -> Url {
+    Traverse::new(document.clone())
+        .filter(|node| node_is_element("base", node))
+        .find_map(|node| node_attr("href", &node))
+        .and_then(|href| page_url.join(href.trim()).ok())
+        .unwrap_or_else(|| page_url.clone())
+}
+
+/// Resolves the link against the base URL, and normalizes it.
+///
+/// The fragment is removed. Returns `None` if the link is invalid or not an
+/// HTTP(S) URL, such as `mailto:` and `javascript:` links.
+pub fn resolve_url(base: &Url, href: &str) -> Option<Url> {
+    let mut url = base.join(href.trim()).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    url.set_fragment(None);
+    Some(url)
+}
+
+/// Loads the page and parses it.
+pub async fn load_dom_async(url: &str, client: &HttpClient) -> Result<RcDom, Error> {
     log::trace!("Loading page: {:?}", url);
-    let dom = {
-        use html5ever::tendril::stream::TendrilSink;
-
-        let top_text = client()?.get(url).send()?.error_for_status()?.text()?;
-        parse_document(RcDom::default(), Default::default())
-            .from_utf8()
-            .read_from(&mut std::io::Cursor::new(&top_text))?
-    };
+    let top_text = client.fetch_text(url).await?;
+    let dom = parse_dom(&top_text);
     log::trace!("DOM errors for {:?}: {:#?}", url, dom.errors);
 
     Ok(dom)
 }
 
+/// Parses the HTML document.
+pub fn parse_dom(html: &str) -> RcDom {
+    use html5ever::tendril::{stream::TendrilSink, StrTendril};
+
+    parse_document(RcDom::default(), Default::default()).one(StrTendril::from(html))
+}
+
 pub fn attrs_has_id<'a>(id: &str, attrs: impl IntoIterator<Item = &'a Attribute>) -> bool {
     attrs
         .into_iter()
@@ -80,6 +93,30 @@ pub fn node_has_class(class: &str, node: &Handle) -> bool {
     }
 }
 
+pub fn node_is_element(name: &str, node: &Handle) -> bool {
+    match &node.data {
+        NodeData::Element { name: elem, .. } => &elem.local == name,
+        _ => false,
+    }
+}
+
+pub fn node_attr(name: &str, node: &Handle) -> Option<String> {
+    match &node.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|attr| &attr.name.local == name)
+            .map(|attr| attr.value.to_string()),
+        _ => None,
+    }
+}
+
+pub fn parent(node: &Handle) -> Option<Handle> {
+    let parent = node.parent.take();
+    node.parent.set(parent.clone());
+    parent.and_then(|parent| parent.upgrade())
+}
+
 pub fn inner_text(node: Handle) -> String {
     let mut buf = String::new();
     for node in Traverse::new(node) {
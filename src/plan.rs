@@ -0,0 +1,260 @@
+//! Download plan.
+//!
+//! A plan lists the fankits and the images which would be downloaded, without
+//! writing anything to the destination directory.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+};
+
+use crate::{
+    download::{load_item, DownloadConfig},
+    error::Error,
+    event::Event,
+    fankit::{FankitId, Site},
+    http::{block_on, HttpClient},
+    manifest::{ItemRecord, Manifest},
+};
+
+/// Plan of a fankit item.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ItemPlan {
+    /// ID.
+    id: FankitId,
+    /// Section of the site the item belongs to.
+    section: String,
+    /// Item name.
+    item_name: String,
+    /// Item directory name, relative to the destination directory.
+    dir: String,
+    /// Whether the item is recorded in the manifest.
+    recorded: bool,
+    /// Images, in the page order.
+    images: Vec<ImagePlan>,
+}
+
+impl ItemPlan {
+    /// Returns the ID.
+    pub fn id(&self) -> FankitId {
+        self.id
+    }
+
+    /// Returns the section of the site the item belongs to.
+    pub fn section(&self) -> &str {
+        &self.section
+    }
+
+    /// Returns the item name.
+    pub fn item_name(&self) -> &str {
+        &self.item_name
+    }
+
+    /// Returns the item directory name.
+    pub fn dir(&self) -> &str {
+        &self.dir
+    }
+
+    /// Returns whether the item is recorded in the manifest, i.e. is going
+    /// to be repaired rather than newly downloaded.
+    pub fn is_recorded(&self) -> bool {
+        self.recorded
+    }
+
+    /// Returns the images.
+    pub fn images(&self) -> &[ImagePlan] {
+        &self.images
+    }
+}
+
+impl fmt::Display for ItemPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = if self.recorded { "repair" } else { "new" };
+        writeln!(
+            f,
+            "[{}] {} ({}/{})",
+            state,
+            self.item_name,
+            self.section,
+            self.id.to_usize()
+        )?;
+        for image in &self.images {
+            let state = if image.exists { "exists" } else { "fetch" };
+            writeln!(
+                f,
+                "    [{}] {} -> {}/{}",
+                state, image.url, self.dir, image.filename
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Plan of an image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImagePlan {
+    /// Source URL.
+    url: String,
+    /// File name in the item directory.
+    filename: String,
+    /// Whether the file already exists.
+    exists: bool,
+}
+
+impl ImagePlan {
+    /// Returns the source URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the file name in the item directory.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Returns whether the file already exists.
+    ///
+    /// Existing files are not fetched again if they are recorded as intact
+    /// in the manifest.
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+}
+
+/// Returns the plan to download the fankits which are not downloaded yet,
+/// blocking the current thread.
+///
+/// This must not be called from async context.
+pub fn plan_fankits(
+    dest_dir: &Path,
+    fankits: &HashSet<FankitId>,
+    manifest: &Manifest,
+    client: &HttpClient,
+    site: &Site,
+    config: &DownloadConfig,
+) -> Result<Vec<ItemPlan>, Error> {
+    let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+    for &fankit in fankits {
+        fankits_tx
+            .send((site.clone(), fankit))
+            .map_err(Error::internal)?;
+    }
+    drop(fankits_tx);
+
+    block_on(plan_fankits_async(
+        dest_dir, fankits_rx, manifest, client, config,
+    ))?
+}
+
+/// Returns the plan to download the fankits received from the channel with
+/// their sites which are not downloaded yet, until the channel is closed.
+///
+/// The item pages are loaded as [`download_fankits_async`] does, but nothing
+/// is written to the destination directory.
+/// The plans are sorted by the IDs.
+///
+/// [`download_fankits_async`]: crate::download::download_fankits_async
+pub async fn plan_fankits_async(
+    dest_dir: &Path,
+    mut fankits: mpsc::UnboundedReceiver<(Site, FankitId)>,
+    manifest: &Manifest,
+    client: &HttpClient,
+    config: &DownloadConfig,
+) -> Result<Vec<ItemPlan>, Error> {
+    let permits = Arc::new(Semaphore::new(config.jobs.max(1)));
+
+    let mut seen = HashSet::new();
+    let mut items = JoinSet::new();
+    let mut plans = Vec::new();
+    let mut receiving = true;
+    loop {
+        tokio::select! {
+            fankit = fankits.recv(), if receiving => {
+                let (site, fankit) = match fankit {
+                    Some(v) => v,
+                    None => {
+                        receiving = false;
+                        continue;
+                    }
+                };
+                if !seen.insert(fankit) || !config.filter.accepts_id(fankit) {
+                    continue;
+                }
+                let previous = manifest.item(fankit);
+                if previous.is_some_and(|item| item.is_intact(dest_dir)) {
+                    continue;
+                }
+                items.spawn(plan_item(
+                    dest_dir.to_owned(),
+                    site,
+                    fankit,
+                    previous.cloned(),
+                    client.clone(),
+                    config.clone(),
+                    Arc::clone(&permits),
+                ));
+            }
+            Some(plan) = items.join_next() => {
+                plans.extend(plan.map_err(Error::internal)??);
+            }
+            else => break,
+        }
+    }
+
+    plans.sort_by_key(|plan| plan.id);
+    Ok(plans)
+}
+
+/// Loads the fankit item page, and returns the plan.
+///
+/// Returns `None` if the item page does not exist or fails to load, or the
+/// item is rejected by the filter.
+async fn plan_item(
+    dest_dir: PathBuf,
+    site: Site,
+    fankit: FankitId,
+    previous: Option<ItemRecord>,
+    client: HttpClient,
+    config: DownloadConfig,
+    permits: Arc<Semaphore>,
+) -> Result<Option<ItemPlan>, Error> {
+    let _permit = permits.acquire_owned().await.map_err(Error::internal)?;
+    let info = match load_item(&client, &site, fankit, &config.events).await {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    config.events.send(|| Event::item_parsed(&site, &info));
+    if !config.filter.accepts(&info) {
+        log::info!("Skipping filtered item {:?}", info.item_name());
+        return Ok(None);
+    }
+
+    let record = config.item_record(&info, &site, previous.as_ref());
+    let item_dir = dest_dir.join(record.dir());
+    let images = config
+        .image_filenames(&info, &site, previous.as_ref())
+        .into_iter()
+        .map(|(url, filename)| ImagePlan {
+            url: url.to_owned(),
+            exists: item_dir.join(&filename).is_file(),
+            filename,
+        })
+        .collect();
+
+    Ok(Some(ItemPlan {
+        id: fankit,
+        section: site.section().to_owned(),
+        item_name: info.item_name(),
+        dir: record.dir().to_owned(),
+        recorded: previous.is_some(),
+        images,
+    }))
+}
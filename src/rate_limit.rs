@@ -0,0 +1,156 @@
+//! Per-host rate limiter.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+
+/// Kind of requests, limited separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    /// HTML pages.
+    Page,
+    /// Images and other assets.
+    Image,
+}
+
+/// Rate of requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    /// Interval to get a new token.
+    interval: Duration,
+    /// Maximum number of tokens.
+    burst: u32,
+}
+
+impl Rate {
+    /// Creates a new rate.
+    ///
+    /// A request is allowed for every `interval` on average, and at most
+    /// `burst` requests are allowed at once.
+    pub fn new(interval: Duration, burst: u32) -> Self {
+        Self {
+            interval,
+            burst: burst.max(1),
+        }
+    }
+}
+
+/// Token bucket.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    /// The time the bucket will be full at.
+    ///
+    /// Tokens are represented by how far this is in the past, which lets a
+    /// request reserve a future token.
+    full_at: Instant,
+}
+
+impl Bucket {
+    /// Reserves a token, and returns the time to wait before using it.
+    fn reserve(&mut self, rate: Rate, now: Instant) -> Duration {
+        let capacity = rate.interval * rate.burst;
+        // Tokens cannot accumulate more than the capacity.
+        let full_at = self.full_at.max(now);
+        let empty_at = full_at.checked_sub(capacity).unwrap_or(now);
+        let wait = (empty_at + rate.interval).saturating_duration_since(now);
+        self.full_at = full_at + rate.interval;
+        wait
+    }
+}
+
+/// Per-host rate limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Rate for pages.
+    page_rate: Rate,
+    /// Rate for images.
+    image_rate: Rate,
+    /// Buckets for each host and request kind.
+    buckets: Mutex<HashMap<(String, RequestKind), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter.
+    pub fn new(page_rate: Rate, image_rate: Rate) -> Self {
+        Self {
+            page_rate,
+            image_rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Reserves a request to the URL, and returns the time to wait before
+    /// sending it.
+    fn reserve(&self, url: &str, kind: RequestKind) -> Duration {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(ToOwned::to_owned))
+            .unwrap_or_default();
+        let rate = match kind {
+            RequestKind::Page => self.page_rate,
+            RequestKind::Image => self.image_rate,
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets
+            .entry((host, kind))
+            .or_insert(Bucket { full_at: now })
+            .reserve(rate, now)
+    }
+
+    /// Waits until a request to the URL is allowed.
+    pub async fn wait(&self, url: &str, kind: RequestKind) {
+        let wait = self.reserve(url, kind);
+        if wait > Duration::from_secs(0) {
+            log::trace!("Waiting {:?} for {:?} request to {:?}", wait, kind, url);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_limits() {
+        let rate = Rate::new(Duration::from_secs(1), 2);
+        let now = Instant::now();
+        let mut bucket = Bucket { full_at: now };
+        assert_eq!(bucket.reserve(rate, now), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(rate, now), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(rate, now), Duration::from_secs(1));
+        assert_eq!(bucket.reserve(rate, now), Duration::from_secs(2));
+
+        // Tokens do not accumulate beyond the burst size.
+        let later = now + Duration::from_secs(100);
+        assert_eq!(bucket.reserve(rate, later), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(rate, later), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(rate, later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn hosts_and_kinds_are_limited_separately() {
+        let rate = Rate::new(Duration::from_secs(10), 1);
+        let limiter = RateLimiter::new(rate, rate);
+        assert_eq!(
+            limiter.reserve("https://a.example.com/", RequestKind::Page),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            limiter.reserve("https://a.example.com/x.png", RequestKind::Image),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            limiter.reserve("https://b.example.com/", RequestKind::Page),
+            Duration::from_secs(0)
+        );
+        assert!(
+            limiter.reserve("https://a.example.com/2/", RequestKind::Page) > Duration::from_secs(9)
+        );
+    }
+}
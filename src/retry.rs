@@ -0,0 +1,343 @@
+//! Retry policy.
+
+use std::{
+    collections::hash_map::RandomState,
+    error, fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    Response, StatusCode,
+};
+
+use crate::error::Error;
+
+/// Default retryable HTTP status codes.
+pub const DEFAULT_RETRYABLE_STATUSES: &[u16] = &[408, 429, 500, 502, 503, 504];
+
+/// Retry policy.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    max_attempts: u32,
+    /// Backoff before the first retry.
+    backoff_base: Duration,
+    /// Maximum backoff.
+    backoff_max: Duration,
+    /// Ratio of the random jitter to the backoff, in `[0, 1]`.
+    jitter: f64,
+    /// Retryable HTTP status codes.
+    retryable_statuses: Vec<StatusCode>,
+    /// Whether to retry on network errors and transient I/O errors.
+    retry_network_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(60),
+            jitter: 0.5,
+            retryable_statuses: DEFAULT_RETRYABLE_STATUSES
+                .iter()
+                .filter_map(|&code| StatusCode::from_u16(code).ok())
+                .collect(),
+            retry_network_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Sets the maximum number of attempts, including the first one.
+    pub fn max_attempts(self, max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            ..self
+        }
+    }
+
+    /// Sets the backoff before the first retry, and the maximum backoff.
+    ///
+    /// The backoff is doubled for each retry.
+    pub fn backoff(self, base: Duration, max: Duration) -> Self {
+        Self {
+            backoff_base: base,
+            backoff_max: max.max(base),
+            ..self
+        }
+    }
+
+    /// Sets the ratio of the random jitter to the backoff.
+    pub fn jitter(self, jitter: f64) -> Self {
+        Self {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Sets the retryable HTTP status codes.
+    pub fn retryable_statuses(self, statuses: impl IntoIterator<Item = StatusCode>) -> Self {
+        Self {
+            retryable_statuses: statuses.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Sets whether to retry on network errors and transient I/O errors.
+    pub fn retry_network_errors(self, retry: bool) -> Self {
+        Self {
+            retry_network_errors: retry,
+            ..self
+        }
+    }
+
+    /// Runs the operation, retrying on retryable failures.
+    ///
+    /// `target` is used for logging.
+    pub async fn run<T, F, Fut>(&self, target: &str, mut op: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let err = match op().await {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            let delay = match self.retry_delay(attempt, &err) {
+                Some(v) => v,
+                None => return Err(err),
+            };
+            log::warn!(
+                "Failed to fetch {:?} (attempt {}/{}): {}; retrying in {:?}",
+                target,
+                attempt,
+                self.max_attempts,
+                err,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Returns the delay before the next attempt, or `None` if the error
+    /// should not be retried.
+    fn retry_delay(&self, attempt: u32, err: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        match err {
+            Error::HttpStatus(err) => {
+                if !self.retryable_statuses.contains(&err.status) {
+                    return None;
+                }
+                if let Some(retry_after) = err.retry_after {
+                    // Retrying earlier than requested would be refused again.
+                    if retry_after > self.backoff_max {
+                        log::warn!(
+                            "Giving up on {:?}: Retry-After {:?} exceeds the maximum backoff {:?}",
+                            err.url,
+                            retry_after,
+                            self.backoff_max
+                        );
+                        return None;
+                    }
+                    return Some(retry_after);
+                }
+            }
+            Error::Network { .. } if !self.retry_network_errors => return None,
+            Error::Network { source, .. } => {
+                if let Some(err) = source.downcast_ref::<reqwest::Error>() {
+                    let is_transient =
+                        err.is_timeout() || err.is_connect() || err.is_request() || err.is_body();
+                    if !is_transient {
+                        return None;
+                    }
+                } else if let Some(err) = source.downcast_ref::<io::Error>() {
+                    if !is_transient_io_error(err) {
+                        return None;
+                    }
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+
+        Some(self.backoff_for(attempt))
+    }
+
+    /// Returns the backoff after the given attempt, with random jitter.
+    fn backoff_for(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let backoff = self
+            .backoff_base
+            .checked_mul(1 << exp)
+            .map_or(self.backoff_max, |v| v.min(self.backoff_max));
+        backoff.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// Returns whether the I/O error is likely to be transient.
+fn is_transient_io_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Returns a random number in `[0, 1)`.
+fn random_fraction() -> f64 {
+    // `RandomState` is randomly seeded for each instance.
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// HTTP error status with `Retry-After` information.
+#[derive(Debug, Clone)]
+pub struct HttpStatusError {
+    /// URL.
+    url: String,
+    /// Status code.
+    status: StatusCode,
+    /// Delay requested by `Retry-After` header.
+    retry_after: Option<Duration>,
+}
+
+impl HttpStatusError {
+    /// Returns the URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the status code.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the response if the status is successful, or an error
+    /// otherwise.
+    pub fn check(resp: Response) -> Result<Response, Self> {
+        let status = resp.status();
+        if !status.is_client_error() && !status.is_server_error() {
+            return Ok(resp);
+        }
+        Err(Self {
+            url: resp.url().to_string(),
+            status,
+            retry_after: retry_after(resp.headers()),
+        })
+    }
+}
+
+impl error::Error for HttpStatusError {}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {} for {:?}", self.status, self.url)
+    }
+}
+
+/// Parses `Retry-After` header.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::from_secs(0)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_up_to_max() {
+        let policy = RetryPolicy::default()
+            .backoff(Duration::from_secs(1), Duration::from_secs(5))
+            .jitter(0.0);
+        assert_eq!(policy.backoff_for(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff_for(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff_for(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff_for(4), Duration::from_secs(5));
+        assert_eq!(policy.backoff_for(100), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_shortens_backoff() {
+        let policy = RetryPolicy::default()
+            .backoff(Duration::from_secs(4), Duration::from_secs(4))
+            .jitter(0.5);
+        for _ in 0..100 {
+            let backoff = policy.backoff_for(1);
+            assert!(backoff > Duration::from_secs(2) && backoff <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn retry_decision() {
+        let policy = RetryPolicy::default()
+            .max_attempts(3)
+            .backoff(Duration::from_secs(1), Duration::from_secs(30))
+            .jitter(0.0);
+        let status_error = |status: u16, retry_after: Option<u64>| {
+            Error::HttpStatus(HttpStatusError {
+                url: "https://example.com/".to_owned(),
+                status: StatusCode::from_u16(status).unwrap(),
+                retry_after: retry_after.map(Duration::from_secs),
+            })
+        };
+        let io_error =
+            |kind: io::ErrorKind| Error::network("https://example.com/", io::Error::from(kind));
+
+        assert_eq!(
+            policy.retry_delay(1, &status_error(503, None)),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.retry_delay(1, &status_error(429, Some(30))),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(policy.retry_delay(1, &status_error(429, Some(31))), None);
+        assert_eq!(policy.retry_delay(3, &status_error(503, None)), None);
+        assert_eq!(policy.retry_delay(1, &status_error(404, None)), None);
+
+        let reset = io_error(io::ErrorKind::ConnectionReset);
+        assert!(policy.retry_delay(1, &reset).is_some());
+        let denied = io_error(io::ErrorKind::PermissionDenied);
+        assert_eq!(policy.retry_delay(1, &denied), None);
+        assert_eq!(
+            policy.retry_network_errors(false).retry_delay(1, &reset),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+    }
+}
@@ -0,0 +1,395 @@
+//! Whole run of the downloader.
+//!
+//! A run crawls the list pages (or takes the fankits specified directly),
+//! filters the fankits, downloads them or prints the plan, and reports the
+//! summary, as the `priconne-fankit-dl` command does.
+
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    download::{download_fankits_async, DownloadConfig},
+    error::Error,
+    event::{Event, EventSender, Summary},
+    fankit::{
+        crawl_fankits_if_new_fankit_found, crawl_missing_fankits, FankitId, FankitTarget, Site,
+        DEFAULT_SECTION,
+    },
+    file::{remove_temp_files, write_atomically},
+    http::HttpClient,
+    manifest::{Manifest, MANIFEST_FILENAME},
+    plan::plan_fankits_async,
+};
+
+/// Output format of the progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    Text,
+    /// JSON lines of the events.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown output format {:?}", s)),
+        }
+    }
+}
+
+/// Configuration of a run.
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    /// Base URL of the site.
+    base_url: String,
+    /// Sites to crawl.
+    sites: Vec<Site>,
+    /// Fankits to download instead of crawling the list pages.
+    targets: Vec<FankitTarget>,
+    /// Destination directory.
+    dest_dir: PathBuf,
+    /// Whether to walk all list pages.
+    full: bool,
+    /// Whether to print the plan without writing anything.
+    dry_run: bool,
+    /// Output format of the progress.
+    output: OutputFormat,
+    /// Path of the summary report, relative to the destination directory.
+    report: Option<PathBuf>,
+    /// Number of consecutive known list pages to stop the crawl.
+    known_pages_to_stop: usize,
+    /// Downloader configuration.
+    download: DownloadConfig,
+}
+
+impl RunConfig {
+    /// Creates a new configuration crawling the sections of the site.
+    ///
+    /// The destination directory is the current directory by default.
+    ///
+    /// Returns `Error::Config` if the base URL is invalid.
+    pub fn new<S: AsRef<str>>(
+        base_url: &str,
+        sections: impl IntoIterator<Item = S>,
+    ) -> Result<Self, Error> {
+        let sites = sections
+            .into_iter()
+            .map(|section| Site::new(base_url, section.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            base_url: base_url.to_owned(),
+            sites,
+            targets: Vec::new(),
+            dest_dir: PathBuf::from("."),
+            full: false,
+            dry_run: false,
+            output: OutputFormat::Text,
+            report: None,
+            known_pages_to_stop: 1,
+            download: DownloadConfig::default(),
+        })
+    }
+
+    /// Returns the sites to crawl.
+    pub fn sites(&self) -> &[Site] {
+        &self.sites
+    }
+
+    /// Sets the fankits to download instead of crawling the list pages.
+    pub fn targets(self, targets: Vec<FankitTarget>) -> Self {
+        Self { targets, ..self }
+    }
+
+    /// Sets the destination directory.
+    pub fn dest_dir(self, dest_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dest_dir: dest_dir.into(),
+            ..self
+        }
+    }
+
+    /// Sets whether to walk all list pages and download every fankit missing
+    /// from the destination, instead of stopping at known list pages.
+    pub fn full(self, full: bool) -> Self {
+        Self { full, ..self }
+    }
+
+    /// Sets whether to print the plan instead of downloading anything.
+    pub fn dry_run(self, dry_run: bool) -> Self {
+        Self { dry_run, ..self }
+    }
+
+    /// Sets the output format of the progress on the standard output.
+    pub fn output(self, output: OutputFormat) -> Self {
+        Self { output, ..self }
+    }
+
+    /// Sets the path of the summary report, relative to the destination
+    /// directory.
+    ///
+    /// The report is not written in dry-run mode.
+    pub fn report(self, report: Option<PathBuf>) -> Self {
+        Self { report, ..self }
+    }
+
+    /// Sets the number of consecutive list pages with only known fankits
+    /// after which the incremental crawl stops.
+    pub fn known_pages_to_stop(self, known_pages_to_stop: usize) -> Self {
+        Self {
+            known_pages_to_stop,
+            ..self
+        }
+    }
+
+    /// Sets the downloader configuration.
+    ///
+    /// The sender of the events is replaced by the run.
+    pub fn download(self, download: DownloadConfig) -> Self {
+        Self { download, ..self }
+    }
+}
+
+/// Runs the downloader, blocking the current thread.
+///
+/// The progress is printed on the standard output in the configured format,
+/// followed by the summary, which is reported even if the run has failed.
+///
+/// This must not be called from async context.
+pub fn run(client: &HttpClient, config: &RunConfig) -> Result<Summary, Error> {
+    let started = Instant::now();
+    let dest_dir = config.dest_dir.as_path();
+    log::debug!("destination directory: {}", dest_dir.display());
+
+    if !config.dry_run {
+        remove_temp_files(dest_dir).map_err(|e| Error::Io {
+            path: dest_dir.to_path_buf(),
+            source: e,
+        })?;
+    }
+
+    let manifest_path = dest_dir.join(MANIFEST_FILENAME);
+    let mut manifest = Manifest::load(&manifest_path)?;
+    if !manifest_path.exists() {
+        // Import the items downloaded by the versions without manifests.
+        manifest = Manifest::import_legacy(dest_dir)?;
+        if manifest.items().next().is_some() {
+            log::info!(
+                "Imported {} fankits from {}",
+                manifest.items().count(),
+                dest_dir.display()
+            );
+            if !config.dry_run {
+                manifest.save(&manifest_path)?;
+            }
+        }
+    }
+    let items_to_repair = manifest.items_to_repair(dest_dir).collect::<HashSet<_>>();
+    if !items_to_repair.is_empty() {
+        log::info!("Found incomplete fankits: {:?}", items_to_repair);
+    }
+
+    let mut known_items = manifest.items().collect::<HashSet<_>>();
+    if config.full {
+        // Broken items are also reported as missing.
+        known_items.retain(|fankit| !items_to_repair.contains(fankit));
+    } else {
+        // Filtered fankits are checked again only in full-archive mode, so
+        // that they are found after the filter is changed.
+        known_items.extend(manifest.filtered());
+    }
+    let repairs = items_to_repair
+        .iter()
+        .map(|&fankit| {
+            let section = manifest
+                .item(fankit)
+                .map_or(DEFAULT_SECTION, |item| item.section());
+            Ok((Site::new(&config.base_url, section)?, fankit))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let runtime = tokio::runtime::Runtime::new().map_err(Error::internal)?;
+    let (mut summary, result) = runtime.block_on(async {
+        let (events, events_rx) = EventSender::channel();
+        let collector = tokio::spawn(collect_events(events_rx, config.output));
+        let download_config = config.download.clone().events(events.clone());
+
+        // Crawl the list pages and download the fankits found concurrently.
+        let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+        let crawl_events = events.clone();
+        let crawl = async move {
+            let send = |site: &Site, fankit| {
+                fankits_tx
+                    .send((site.clone(), fankit))
+                    .map_err(|e| Error::Internal(e.into()))
+            };
+            if !config.targets.is_empty() {
+                // Download the specified fankits only, without crawling.
+                for target in &config.targets {
+                    for fankit in target.ids() {
+                        send(target.site(), fankit)?;
+                    }
+                }
+                return Ok(());
+            }
+            for (site, fankit) in &repairs {
+                send(site, *fankit)?;
+            }
+            for site in &config.sites {
+                if config.full {
+                    let missing = crawl_missing_fankits(
+                        &known_items,
+                        client,
+                        site,
+                        &fankits_tx,
+                        &crawl_events,
+                    )
+                    .await?;
+                    log::info!(
+                        "Found {} fankits missing in section {:?}: {:?}",
+                        missing.len(),
+                        site.section(),
+                        missing
+                    );
+                    continue;
+                }
+                let found = crawl_fankits_if_new_fankit_found(
+                    &known_items,
+                    client,
+                    site,
+                    config.known_pages_to_stop,
+                    &fankits_tx,
+                    &crawl_events,
+                )
+                .await?;
+                if !found {
+                    log::info!(
+                        "There seems to be no new fankits in section {:?}",
+                        site.section()
+                    );
+                }
+            }
+            Ok(())
+        };
+        let download = download_or_plan(
+            config,
+            fankits_rx,
+            &mut manifest,
+            client,
+            &download_config,
+            &events,
+        );
+
+        let (crawl_result, download_result) = tokio::join!(crawl, download);
+        // Close the event channel and wait for all events to be collected.
+        drop(download_config);
+        drop(events);
+        // A failed collector loses the counts, but the error is still reported.
+        let (summary, collector_result) = match collector.await {
+            Ok(summary) => (summary, Ok(())),
+            Err(e) => (Summary::default(), Err(Error::Internal(e.into()))),
+        };
+        (
+            summary,
+            download_result.and(crawl_result).and(collector_result),
+        )
+    });
+
+    // The summary is reported even if the run has failed.
+    summary.set_elapsed(started.elapsed());
+    match config.output {
+        OutputFormat::Text => print!("{}", summary),
+        OutputFormat::Json => print_event(&Event::Summary(summary.clone())),
+    }
+    let report_result = match (&config.report, config.dry_run) {
+        (Some(report), false) => write_report(&dest_dir.join(report), &summary),
+        _ => Ok(()),
+    };
+
+    // The error of the run takes precedence over the report.
+    result.and(report_result).map(|()| summary)
+}
+
+/// Writes the summary report in JSON to the file.
+fn write_report(path: &Path, summary: &Summary) -> Result<(), Error> {
+    write_atomically(path, |writer| {
+        serde_json::to_writer_pretty(&mut *writer, summary)?;
+        writeln!(writer)
+    })
+    .map_err(|e| Error::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })
+}
+
+/// Collects the events into the summary, printing them as JSON lines in
+/// `json` mode.
+async fn collect_events(
+    mut events: mpsc::UnboundedReceiver<Event>,
+    output: OutputFormat,
+) -> Summary {
+    let mut summary = Summary::default();
+    while let Some(event) = events.recv().await {
+        summary.record(&event);
+        if output == OutputFormat::Json {
+            print_event(&event);
+        }
+    }
+    summary
+}
+
+/// Prints the event as a JSON line.
+fn print_event(event: &Event) {
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{}", line),
+        Err(e) => log::error!("Failed to serialize the event {:?}: {}", event, e),
+    }
+}
+
+/// Downloads the fankits received from the channel, or prints the plan in
+/// dry-run mode.
+async fn download_or_plan(
+    config: &RunConfig,
+    fankits: mpsc::UnboundedReceiver<(Site, FankitId)>,
+    manifest: &mut Manifest,
+    client: &HttpClient,
+    download_config: &DownloadConfig,
+    events: &EventSender,
+) -> Result<(), Error> {
+    let dest_dir = config.dest_dir.as_path();
+    if !config.dry_run {
+        return download_fankits_async(dest_dir, fankits, manifest, client, download_config).await;
+    }
+
+    let plans = plan_fankits_async(dest_dir, fankits, manifest, client, download_config).await?;
+    if config.output == OutputFormat::Json {
+        for plan in plans {
+            events.send(|| Event::ItemPlanned(plan));
+        }
+        return Ok(());
+    }
+    for plan in &plans {
+        print!("{}", plan);
+    }
+    let images = plans
+        .iter()
+        .flat_map(|plan| plan.images())
+        .filter(|image| !image.exists())
+        .count();
+    println!(
+        "{} fankits and {} images would be downloaded",
+        plans.len(),
+        images
+    );
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Metadata sidecar of fankit items.
+//!
+//! The sidecar is a JSON file in each item directory describing the item and
+//! its images, so that the item can be identified without the manifest.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+use crate::{
+    error::Error,
+    fankit::{Asset, FankitId, FankitInfo, Site},
+    file::write_atomically,
+    manifest::{ImageRecord, ItemRecord},
+    naming::url_filename,
+};
+
+/// Sidecar file name in the item directory.
+pub const SIDECAR_FILENAME: &str = "fankit.json";
+
+/// Metadata sidecar of a fankit item.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Sidecar<'a> {
+    /// ID.
+    id: FankitId,
+    /// Section of the site the item belongs to.
+    section: &'a str,
+    /// Fankit type.
+    #[serde(rename = "type")]
+    ty: &'a str,
+    /// Title.
+    title: &'a str,
+    /// Description.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<&'a str>,
+    /// Publication date.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<&'a str>,
+    /// URL of the item page.
+    url: String,
+    /// Time the sidecar is written, in HTTP date format.
+    updated_at: String,
+    /// Images.
+    images: Vec<SidecarImage<'a>>,
+}
+
+impl<'a> Sidecar<'a> {
+    /// Creates a sidecar of the item in the site, with the images in the
+    /// record.
+    pub fn new(info: &'a FankitInfo, site: &'a Site, record: &'a ItemRecord) -> Self {
+        Self {
+            id: info.id(),
+            section: site.section(),
+            ty: info.ty(),
+            title: info.title(),
+            description: info.description(),
+            published: info.published(),
+            url: info.id().to_url(site),
+            updated_at: httpdate::fmt_http_date(SystemTime::now()),
+            images: record
+                .images()
+                .iter()
+                .map(|record| {
+                    let asset = info
+                        .assets()
+                        .iter()
+                        .find(|asset| asset.url() == record.url());
+                    SidecarImage {
+                        original_filename: url_filename(record.url(), 0),
+                        position: asset.map(Asset::position),
+                        label: asset.and_then(Asset::label),
+                        record,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the sidecar into the item directory atomically.
+    pub fn save(&self, item_dir: &Path) -> Result<(), Error> {
+        let path = item_dir.join(SIDECAR_FILENAME);
+        write_atomically(&path, |writer| {
+            serde_json::to_writer_pretty(&mut *writer, self).map_err(io::Error::from)?;
+            writeln!(writer)
+        })
+        .map_err(|e| Error::io(path, e))
+    }
+}
+
+/// Image in the sidecar.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct SidecarImage<'a> {
+    /// File name in the source URL.
+    original_filename: String,
+    /// Position in the page, starting from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<usize>,
+    /// Label text near the image in the page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    /// Record of the image.
+    #[serde(flatten)]
+    record: &'a ImageRecord,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize() {
+        let html = r#"<html><body><div id="contents">
+            <p class="fankit-type">Wallpaper</p>
+            <h2 class="title">Title</h2>
+            <a href="https://example.com/a.png?v=1">a</a>
+        </div></body></html>"#;
+        let site = Site::default();
+        let info =
+            FankitInfo::parse_html(FankitId::new(42), &site, html).expect("should be parsable");
+        let mut record = ItemRecord::new(&info, &site, info.item_name());
+        record.push_image(ImageRecord::incomplete(
+            "https://example.com/a.png?v=1",
+            "a.png",
+        ));
+
+        let json = serde_json::to_value(Sidecar::new(&info, &site, &record)).unwrap();
+        assert_eq!(json["id"], 42);
+        assert_eq!(json["type"], "Wallpaper");
+        assert_eq!(json["url"], "https://priconne-redive.jp/fankit02/42/");
+        assert!(json.get("description").is_none());
+        assert_eq!(json["images"][0]["original_filename"], "a.png");
+        assert_eq!(json["images"][0]["position"], 1);
+        assert_eq!(json["images"][0]["label"], "a");
+        assert_eq!(json["images"][0]["url"], "https://example.com/a.png?v=1");
+        assert_eq!(json["images"][0]["sha256"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,223 @@
+//! Runs the command against the local mock server.
+
+mod common;
+
+use std::{fs, path::Path, process::Command};
+
+use priconne_fankit_dl::manifest::MANIFEST_FILENAME;
+
+use self::common::{test_dir, MockServer};
+
+/// Runs the command downloading the fankits into the directory.
+fn run(server: &MockServer, dest_dir: &Path, args: &[&str]) -> (Option<i32>, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_priconne-fankit-dl"))
+        .arg("--base-url")
+        .arg(server.base_url())
+        .arg("--dest")
+        .arg(dest_dir)
+        .args(["--delay", "0", "--image-delay", "0", "--max-attempts", "1"])
+        .args(args)
+        .output()
+        .expect("failed to run the command");
+    (
+        output.status.code(),
+        String::from_utf8(output.stdout).expect("output should be UTF-8"),
+    )
+}
+
+#[test]
+fn summary_is_reported() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-summary");
+
+    let (status, stdout) = run(
+        &server,
+        &dest_dir,
+        &["--report", "report.json", "101..=102"],
+    );
+
+    assert_eq!(status, Some(0));
+    assert!(stdout.contains("Items: 2 downloaded, 0 skipped, 0 failed"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest_dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["items_done"], 2);
+    assert_eq!(report["images_downloaded"], 3);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn failed_images_exit_with_error() {
+    let server = MockServer::start();
+    server.set_status("/wp-content/uploads/2020/01/pecorine_sp.png", 404);
+    let dest_dir = test_dir("cli-failure");
+
+    let (status, stdout) = run(&server, &dest_dir, &["--output", "json", "102"]);
+
+    assert_eq!(status, Some(8));
+    let events = stdout
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert!(events
+        .iter()
+        .any(|event| event["event"] == "image_failed" && event["id"] == 102));
+    let summary = events.last().expect("summary should be printed");
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(summary["items_incomplete"], 1);
+    assert_eq!(summary["images_failed"], 1);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn failed_items_are_reported() {
+    let server = MockServer::start();
+    server.set_status("/fankit02/101/", 500);
+    let dest_dir = test_dir("cli-item-failure");
+
+    let (status, stdout) = run(
+        &server,
+        &dest_dir,
+        &["--report", "report.json", "101..=102"],
+    );
+
+    // The other items are still downloaded.
+    assert_eq!(status, Some(8));
+    assert!(stdout.contains("Items: 1 downloaded, 0 skipped, 1 failed"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest_dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["items_done"], 1);
+    assert_eq!(report["items_failed"], 1);
+    assert!(dest_dir.join(MANIFEST_FILENAME).is_file());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn summary_is_reported_on_error() {
+    let server = MockServer::start();
+    server.set_status("/fankit02/", 500);
+    let dest_dir = test_dir("cli-error");
+
+    let (status, stdout) = run(&server, &dest_dir, &["--report", "report.json"]);
+
+    assert_eq!(status, Some(4));
+    assert!(stdout.contains("Items: 0 downloaded, 0 skipped, 0 failed"));
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest_dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["list_pages"], 0);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn legacy_items_are_imported() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-import");
+    // Written by the versions without manifests.
+    let legacy_dir = dest_dir.join("101-アイコン-コッコロ");
+    fs::create_dir_all(&legacy_dir).unwrap();
+    fs::write(legacy_dir.join("kokkoro_icon.png"), b"legacy").unwrap();
+    fs::create_dir_all(dest_dir.join("notes")).unwrap();
+
+    let (status, stdout) = run(&server, &dest_dir, &["101..=102"]);
+
+    assert_eq!(status, Some(0));
+    assert!(stdout.contains("Items: 1 downloaded, 1 skipped, 0 failed"));
+    assert!(!server.requests().contains(&"/fankit02/101/".to_owned()));
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest_dir.join(MANIFEST_FILENAME)).unwrap()).unwrap();
+    let item = &manifest["items"]["101"];
+    assert_eq!(item["dir"], "101-アイコン-コッコロ");
+    assert_eq!(item["title"], "コッコロ");
+    assert_eq!(item["images"][0]["filename"], "kokkoro_icon.png");
+    assert_eq!(item["images"][0]["size"], 6);
+    assert!(manifest["items"]["102"].is_object());
+    assert_eq!(
+        fs::read(legacy_dir.join("kokkoro_icon.png")).unwrap(),
+        b"legacy"
+    );
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn invalid_base_url_is_rejected() {
+    let dest_dir = test_dir("cli-base-url");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_priconne-fankit-dl"))
+        .args(["--base-url", "priconne-redive.jp", "--dest"])
+        .arg(&dest_dir)
+        .arg("102")
+        .status()
+        .expect("failed to run the command");
+
+    assert_eq!(status.code(), Some(2));
+    assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 0);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn too_large_id_ranges_are_rejected() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-large-range");
+
+    let (status, _) = run(&server, &dest_dir, &["1..=100000000"]);
+
+    assert_eq!(status, Some(2));
+    assert!(server.requests().is_empty());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn retry_statuses_are_followed_by_targets() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-retry-status");
+
+    let (status, stdout) = run(&server, &dest_dir, &["--retry-status", "503", "102"]);
+
+    assert_eq!(status, Some(0));
+    // Only the target is downloaded, without crawling.
+    assert!(stdout.contains("List pages: 0 loaded"));
+    assert!(stdout.contains("Items: 1 downloaded"));
+    assert!(dest_dir.join("102-壁紙-ペコリーヌ").is_dir());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn asset_kinds_are_followed_by_targets() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-asset-kinds");
+
+    let (status, stdout) = run(&server, &dest_dir, &["--asset-kinds", "document", "106"]);
+
+    assert_eq!(status, Some(0));
+    assert!(stdout.contains("List pages: 0 loaded"));
+    assert!(stdout.contains("Images: 1 downloaded"));
+    assert!(dest_dir.join("106-壁紙-ユキ/yuki_guide.pdf").is_file());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn filtered_fankits_are_known_to_the_crawl() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("cli-filtered");
+
+    let (status, _) = run(&server, &dest_dir, &["--exclude-type", "アイコン"]);
+    assert_eq!(status, Some(0));
+    assert!(server.requests().contains(&"/fankit02/101/".to_owned()));
+
+    // The first list page has only downloaded and filtered fankits.
+    let requests = server.requests().len();
+    let (status, stdout) = run(&server, &dest_dir, &["--exclude-type", "アイコン"]);
+    assert_eq!(status, Some(0));
+    assert!(stdout.contains("List pages: 1 loaded"));
+    assert_eq!(server.requests()[requests..], ["/fankit02/".to_owned()]);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
@@ -0,0 +1,302 @@
+//! Test utilities: a local HTTP server serving the site fixtures.
+
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use percent_encoding::percent_decode_str;
+use priconne_fankit_dl::{
+    fankit::{Site, DEFAULT_SECTION},
+    http::{HttpClient, HttpConfig},
+    rate_limit::{Rate, RateLimiter},
+    retry::RetryPolicy,
+};
+
+/// `Last-Modified` header value sent for the fixtures other than HTML.
+pub const LAST_MODIFIED: &str = "Fri, 10 Jan 2020 00:00:00 GMT";
+
+/// Placeholder in the fixtures replaced with the base URL of the server.
+const BASE_URL_PLACEHOLDER: &str = "{{base_url}}";
+
+/// Returns the directory of the site fixtures.
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/site")
+}
+
+/// Returns a fresh temporary directory for the test.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "priconne-fankit-dl-it-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("failed to create a test directory");
+    dir
+}
+
+/// Returns an HTTP client suitable for the mock server.
+pub fn client() -> HttpClient {
+    let rate = Rate::new(Duration::from_millis(0), 1);
+    HttpClient::new(
+        &HttpConfig::default().read_timeout(Some(Duration::from_secs(10))),
+        RetryPolicy::default()
+            .max_attempts(2)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10)),
+        RateLimiter::new(rate, rate),
+    )
+    .expect("failed to create an HTTP client")
+}
+
+/// Shared state of the mock server.
+#[derive(Debug, Default)]
+struct State {
+    /// Paths of the requests received, in order.
+    requests: Vec<String>,
+    /// Status codes overriding the fixtures, for each path.
+    statuses: HashMap<String, u16>,
+    /// `Range` headers of the requests received, with the paths, in order.
+    ranges: Vec<(String, String)>,
+    /// Paths whose partial responses have wrong `Content-Range`.
+    broken_ranges: HashSet<String>,
+}
+
+/// Local HTTP server serving the files in the fixtures directory.
+///
+/// `index.html` is served for paths ending with a slash, and
+/// `{{base_url}}` in HTML files is replaced with the base URL of the server.
+///
+/// Files other than HTML are sent with `Last-Modified` and `ETag` headers,
+/// and `Range` headers of the form `bytes={start}-` are honored unless
+/// `If-Range` header does not match either of them.
+#[derive(Debug)]
+pub struct MockServer {
+    /// Address of the server.
+    addr: SocketAddr,
+    /// Shared state.
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    /// Starts a server serving the site fixtures.
+    pub fn start() -> Self {
+        Self::with_root(fixtures_dir())
+    }
+
+    /// Starts a server serving the files in the given directory.
+    pub fn with_root(root: PathBuf) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind the mock server");
+        let addr = listener.local_addr().expect("failed to get the address");
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                let root = root.clone();
+                let state = Arc::clone(&server_state);
+                thread::spawn(move || {
+                    // Errors are reported to the client as failed requests.
+                    let _ = handle(stream, &root, addr, &state);
+                });
+            }
+        });
+
+        Self { addr, state }
+    }
+
+    /// Returns the base URL of the server, without the trailing slash.
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Returns the default section of the site served by the server.
+    pub fn site(&self) -> Site {
+        self.section(DEFAULT_SECTION)
+    }
+
+    /// Returns the section of the site served by the server.
+    pub fn section(&self, section: &str) -> Site {
+        Site::new(&self.base_url(), section).expect("the base URL should be valid")
+    }
+
+    /// Returns the paths of the requests received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the paths and the `Range` headers of the ranged requests
+    /// received so far.
+    pub fn range_requests(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().ranges.clone()
+    }
+
+    /// Makes the server respond to the ranged requests for the path with
+    /// the whole content labeled as partial content, as if the server
+    /// miscalculated the range.
+    pub fn break_ranges(&self, path: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .broken_ranges
+            .insert(path.to_owned());
+    }
+
+    /// Makes the server respond to the path with the given status.
+    pub fn set_status(&self, path: &str, status: u16) {
+        self.state
+            .lock()
+            .unwrap()
+            .statuses
+            .insert(path.to_owned(), status);
+    }
+}
+
+/// Handles a connection.
+fn handle(
+    stream: TcpStream,
+    root: &Path,
+    addr: SocketAddr,
+    state: &Mutex<State>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_owned();
+    let range = headers.get("range").cloned();
+    let (status, broken_range) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(path.clone());
+        if let Some(range) = &range {
+            state.ranges.push((path.clone(), range.clone()));
+        }
+        (
+            state.statuses.get(&path).copied(),
+            state.broken_ranges.contains(&path),
+        )
+    };
+
+    let response = match status {
+        Some(status) => Err(status),
+        None => load(root, &path, addr).ok_or(404),
+    };
+    let mut stream = stream;
+    match response {
+        Ok((content_type, body)) if content_type.starts_with("text/html") => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len(),
+            )?;
+            stream.write_all(&body)?;
+        }
+        Ok((content_type, body)) => {
+            let len = body.len();
+            let etag = format!("\"{}\"", len);
+            let validators = format!("Last-Modified: {}\r\nETag: {}\r\n", LAST_MODIFIED, etag);
+            let if_range_matches = headers
+                .get("if-range")
+                .is_none_or(|validator| *validator == etag || validator == LAST_MODIFIED);
+            let start = range
+                .as_deref()
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.strip_suffix('-'))
+                .and_then(|start| start.parse::<usize>().ok())
+                .filter(|_| if_range_matches);
+            match start {
+                Some(start) if start >= len => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        len
+                    )?;
+                }
+                Some(start) => {
+                    // A broken server sends the whole content.
+                    let start = if broken_range { 0 } else { start };
+                    write!(
+                        stream,
+                        "HTTP/1.1 206 Partial Content\r\nContent-Type: {}\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n{}Connection: close\r\n\r\n",
+                        content_type,
+                        len - start,
+                        start,
+                        len - 1,
+                        len,
+                        validators
+                    )?;
+                    stream.write_all(&body[start..])?;
+                }
+                None => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n",
+                        content_type,
+                        len,
+                        validators
+                    )?;
+                    stream.write_all(&body)?;
+                }
+            }
+        }
+        Err(status) => {
+            write!(
+                stream,
+                "HTTP/1.1 {} Error\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )?;
+        }
+    }
+    stream.flush()
+}
+
+/// Loads the fixture for the path, and returns the content type and the body.
+fn load(root: &Path, path: &str, addr: SocketAddr) -> Option<(&'static str, Vec<u8>)> {
+    let path = path.split('?').next().unwrap_or_default();
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    if path.split('/').any(|seg| seg == "..") {
+        return None;
+    }
+    let mut file = root.join(path.trim_start_matches('/'));
+    if path.ends_with('/') {
+        file.push("index.html");
+    }
+    let body = fs::read(&file).ok()?;
+    match file.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => {
+            let html = String::from_utf8(body).ok()?;
+            let html = html.replace(BASE_URL_PLACEHOLDER, &format!("http://{}", addr));
+            Some(("text/html; charset=utf-8", html.into_bytes()))
+        }
+        Some("png") => Some(("image/png", body)),
+        Some("jpg") => Some(("image/jpeg", body)),
+        _ => Some(("application/octet-stream", body)),
+    }
+}
@@ -0,0 +1,1219 @@
+//! Crawl, parse, and download against the local mock server.
+
+mod common;
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use priconne_fankit_dl::{
+    download::{download_fankits, download_fankits_async, DownloadConfig},
+    event::{Event, EventSender, SkipReason, Summary},
+    fankit::{
+        crawl_fankits_if_new_fankit_found, get_fankits_if_new_fankit_found, get_missing_fankits,
+        AssetKind, FankitId, FankitListPageIndex, FankitTarget, DEFAULT_SECTION,
+    },
+    filter::{FankitFilter, TitlePattern},
+    manifest::{Manifest, MANIFEST_FILENAME},
+    naming::NameTemplate,
+    plan::plan_fankits,
+    run::{run, OutputFormat, RunConfig},
+    sidecar::SIDECAR_FILENAME,
+    Error,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use self::common::{client, fixtures_dir, test_dir, MockServer, LAST_MODIFIED};
+
+/// Returns the set of fankit IDs.
+fn ids(ids: &[usize]) -> HashSet<FankitId> {
+    ids.iter().copied().map(FankitId::new).collect()
+}
+
+#[test]
+fn load_list_page() {
+    let server = MockServer::start();
+    let (fankits, list_pages) = FankitListPageIndex::new(1)
+        .load(&client(), &server.site())
+        .expect("list page should be loaded");
+
+    assert_eq!(
+        fankits.into_iter().collect::<HashSet<_>>(),
+        ids(&[101, 102])
+    );
+    assert!(list_pages.contains(&FankitListPageIndex::new(2)));
+}
+
+#[test]
+fn load_item() {
+    let server = MockServer::start();
+    let info = FankitId::new(102)
+        .load(&client(), &server.site())
+        .expect("item should be loaded");
+
+    assert_eq!(info.id(), FankitId::new(102));
+    assert_eq!(info.ty(), "壁紙");
+    assert_eq!(info.title(), "ペコリーヌ");
+    assert_eq!(
+        info.image_urls().collect::<HashSet<_>>(),
+        [
+            format!(
+                "{}/wp-content/uploads/2020/01/pecorine_pc.png",
+                server.base_url()
+            ),
+            format!(
+                "{}/wp-content/uploads/2020/01/pecorine_sp.png",
+                server.base_url()
+            ),
+        ]
+        .iter()
+        .map(String::as_str)
+        .collect()
+    );
+}
+
+#[test]
+fn layout_change_is_reported() {
+    let server = MockServer::start();
+    match FankitId::new(103).load(&client(), &server.site()) {
+        Err(Error::LayoutChanged { url, selector }) => {
+            assert_eq!(url, format!("{}/fankit02/103/", server.base_url()));
+            assert_eq!(selector, "#contents .title");
+        }
+        v => panic!("unexpected result: {:?}", v),
+    }
+}
+
+#[test]
+fn http_error_is_reported() {
+    let server = MockServer::start();
+    server.set_status("/fankit02/102/", 404);
+    match FankitId::new(102).load(&client(), &server.site()) {
+        Err(Error::HttpStatus(e)) => assert_eq!(e.status().as_u16(), 404),
+        v => panic!("unexpected result: {:?}", v),
+    }
+}
+
+#[test]
+fn crawl_walks_all_list_pages() {
+    let server = MockServer::start();
+    let found = get_fankits_if_new_fankit_found(ids(&[99, 100]), &client(), &server.site(), 2)
+        .expect("crawl should succeed");
+
+    assert_eq!(found, Some(ids(&[99, 100, 101, 102])));
+}
+
+#[test]
+fn crawl_stops_at_known_list_page() {
+    let server = MockServer::start();
+    let found = get_fankits_if_new_fankit_found(ids(&[99, 100, 101]), &client(), &server.site(), 1)
+        .expect("crawl should succeed");
+
+    assert_eq!(found, Some(ids(&[100, 101, 102])));
+    assert_eq!(
+        server.requests(),
+        vec!["/fankit02/".to_owned(), "/fankit02/page/2/".to_owned()]
+    );
+}
+
+#[test]
+fn crawl_stops_after_known_list_pages() {
+    let server = MockServer::start();
+    let found = get_fankits_if_new_fankit_found(ids(&[99, 100, 101]), &client(), &server.site(), 2)
+        .expect("crawl should succeed");
+
+    assert_eq!(found, Some(ids(&[99, 100, 101, 102])));
+    assert_eq!(
+        server.requests(),
+        vec![
+            "/fankit02/".to_owned(),
+            "/fankit02/page/2/".to_owned(),
+            "/fankit02/page/3/".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn crawl_stops_if_first_page_is_known() {
+    let server = MockServer::start();
+    let found = get_fankits_if_new_fankit_found(ids(&[101, 102]), &client(), &server.site(), 1)
+        .expect("crawl should succeed");
+
+    assert_eq!(found, None);
+    assert_eq!(server.requests(), vec!["/fankit02/".to_owned()]);
+}
+
+#[test]
+fn full_crawl_finds_missing_fankits() {
+    let server = MockServer::start();
+    // The first list page has no new fankits, but an older one is missing.
+    let missing = get_missing_fankits(ids(&[101, 102]), &client(), &server.site())
+        .expect("crawl should succeed");
+
+    assert_eq!(missing, ids(&[99, 100]));
+    assert!(server.requests().contains(&"/fankit02/page/2/".to_owned()));
+}
+
+#[test]
+fn download_all() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("download-all");
+
+    let fankits = get_fankits_if_new_fankit_found(Vec::new(), &client, &site, 1)
+        .expect("crawl should succeed")
+        .expect("new fankits should be found");
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &fankits,
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2),
+    )
+    .expect("download should succeed");
+
+    let fixture = |name: &str| {
+        fs::read(fixtures_dir().join("wp-content/uploads").join(name))
+            .expect("fixture should exist")
+    };
+    let downloaded = |dir: &str, name: &str| {
+        fs::read(dest_dir.join(dir).join(name)).expect("image should be downloaded")
+    };
+    assert_eq!(
+        downloaded("102-壁紙-ペコリーヌ", "pecorine_pc.png"),
+        fixture("2020/01/pecorine_pc.png")
+    );
+    assert_eq!(
+        downloaded("102-壁紙-ペコリーヌ", "pecorine_sp.png"),
+        fixture("2020/01/pecorine_sp.png")
+    );
+    assert_eq!(
+        downloaded("101-アイコン-コッコロ", "kokkoro_icon.png"),
+        fixture("2020/01/kokkoro_icon.png")
+    );
+    assert_eq!(
+        downloaded("100-壁紙-キャル", "karyl_pc.png"),
+        fixture("2019/12/karyl_pc.png")
+    );
+    assert_eq!(
+        downloaded("99-壁紙-ムイミ", "muimi_pc.png"),
+        fixture("2019/11/muimi_pc.png")
+    );
+
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &fs::read(dest_dir.join("102-壁紙-ペコリーヌ").join(SIDECAR_FILENAME))
+            .expect("sidecar should be written"),
+    )
+    .expect("sidecar should be JSON");
+    assert_eq!(sidecar["id"], 102);
+    assert_eq!(sidecar["type"], "壁紙");
+    assert_eq!(sidecar["title"], "ペコリーヌ");
+    assert_eq!(sidecar["description"], "ペコリーヌの壁紙です。");
+    assert_eq!(sidecar["published"], "2020-01-10");
+    assert_eq!(
+        sidecar["url"],
+        format!("{}/fankit02/102/", server.base_url())
+    );
+    let images = sidecar["images"]
+        .as_array()
+        .expect("images should be listed");
+    assert_eq!(images.len(), 2);
+    let image = images
+        .iter()
+        .find(|image| image["filename"] == "pecorine_pc.png")
+        .expect("image should be listed");
+    let size = fixture("2020/01/pecorine_pc.png").len();
+    assert_eq!(image["original_filename"], "pecorine_pc.png");
+    assert_eq!(image["size"], size);
+    assert_eq!(image["last_modified"], LAST_MODIFIED);
+    assert_eq!(image["etag"], format!("\"{}\"", size));
+    assert_eq!(image["sha256"].as_str().map(str::len), Some(64));
+    assert!(image["downloaded_at"].is_string());
+    assert!(dest_dir
+        .join("101-アイコン-コッコロ")
+        .join(SIDECAR_FILENAME)
+        .is_file());
+
+    let saved =
+        Manifest::load(&dest_dir.join(MANIFEST_FILENAME)).expect("manifest should be saved");
+    assert_eq!(saved, manifest);
+    assert_eq!(
+        saved.items().collect::<HashSet<_>>(),
+        ids(&[99, 100, 101, 102])
+    );
+    assert_eq!(saved.items_to_repair(&dest_dir).count(), 0);
+
+    // Nothing is fetched again for intact items.
+    let requests = server.requests().len();
+    download_fankits(
+        &dest_dir,
+        &fankits,
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2),
+    )
+    .expect("download should succeed");
+    assert_eq!(server.requests().len(), requests);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn missing_image_is_repaired() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("repair");
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2),
+    )
+    .expect("download should succeed");
+    let image_path = dest_dir.join("102-壁紙-ペコリーヌ/pecorine_sp.png");
+    fs::remove_file(&image_path).expect("image should be downloaded");
+    assert_eq!(
+        manifest.items_to_repair(&dest_dir).collect::<Vec<_>>(),
+        vec![FankitId::new(102)]
+    );
+
+    let requests = server.requests().len();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2),
+    )
+    .expect("download should succeed");
+    assert!(image_path.is_file());
+    assert_eq!(
+        server.requests()[requests..],
+        [
+            "/fankit02/102/".to_owned(),
+            "/wp-content/uploads/2020/01/pecorine_sp.png".to_owned(),
+        ]
+    );
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn imported_item_is_repaired_in_place() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("repair-imported");
+    // Written by the versions without manifests.
+    let item_dir = dest_dir.join("102-壁紙-ペコリーヌ");
+    fs::create_dir_all(&item_dir).unwrap();
+    let fixture = |name: &str| {
+        fs::read(fixtures_dir().join("wp-content/uploads/2020/01").join(name))
+            .expect("fixture should exist")
+    };
+    fs::write(item_dir.join("pecorine_pc.png"), fixture("pecorine_pc.png")).unwrap();
+    fs::write(item_dir.join("pecorine_sp.png"), b"").unwrap();
+
+    let mut manifest = Manifest::import_legacy(&dest_dir).expect("import should succeed");
+    assert_eq!(
+        manifest.items_to_repair(&dest_dir).collect::<Vec<_>>(),
+        vec![FankitId::new(102)]
+    );
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2),
+    )
+    .expect("download should succeed");
+
+    // Only the broken image is fetched, into the imported file.
+    assert_eq!(
+        server.requests(),
+        [
+            "/fankit02/102/".to_owned(),
+            "/wp-content/uploads/2020/01/pecorine_sp.png".to_owned(),
+        ]
+    );
+    let mut files = fs::read_dir(&item_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    files.sort_unstable();
+    assert_eq!(
+        files,
+        [SIDECAR_FILENAME, "pecorine_pc.png", "pecorine_sp.png"]
+    );
+    assert_eq!(
+        fs::read(item_dir.join("pecorine_sp.png")).unwrap(),
+        fixture("pecorine_sp.png")
+    );
+    // The imported images are recorded with their URLs.
+    let item = manifest
+        .item(FankitId::new(102))
+        .expect("should be recorded");
+    assert!(item.images().iter().all(|image| !image.url().is_empty()));
+    assert!(item.is_intact(&dest_dir));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+/// Path of the image resumed in the tests.
+const RESUMED_IMAGE: &str = "/wp-content/uploads/2020/01/pecorine_pc.png";
+
+/// Returns the whole content of the image resumed in the tests.
+fn resumed_fixture() -> Vec<u8> {
+    fs::read(fixtures_dir().join(RESUMED_IMAGE.trim_start_matches('/')))
+        .expect("fixture should exist")
+}
+
+/// Leaves a partially downloaded `pecorine_pc.png` of fankit 102 with the
+/// given validator, and returns the path of the image.
+fn seed_part_file(dest_dir: &Path, content: &[u8], validator: &str) -> PathBuf {
+    let item_dir = dest_dir.join("102-壁紙-ペコリーヌ");
+    fs::create_dir_all(&item_dir).expect("failed to create an item directory");
+    fs::write(item_dir.join("pecorine_pc.png.part"), content).expect("failed to write a part");
+    fs::write(item_dir.join("pecorine_pc.png.part.validator"), validator)
+        .expect("failed to write a validator");
+    item_dir.join("pecorine_pc.png")
+}
+
+/// Returns the hex-encoded SHA-256 checksum of the sidecar entry for the
+/// image.
+fn recorded_sha256(image_path: &Path) -> String {
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &fs::read(image_path.with_file_name(SIDECAR_FILENAME)).expect("sidecar should exist"),
+    )
+    .expect("sidecar should be JSON");
+    let filename = image_path.file_name().unwrap().to_str().unwrap();
+    sidecar["images"]
+        .as_array()
+        .expect("images should be listed")
+        .iter()
+        .find(|image| image["filename"] == filename)
+        .and_then(|image| image["sha256"].as_str())
+        .expect("image should be listed")
+        .to_owned()
+}
+
+/// Returns the hex-encoded SHA-256 checksum of the bytes.
+fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[test]
+fn partial_image_is_resumed() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("resume");
+    let fixture = resumed_fixture();
+    let half = fixture.len() / 2;
+    let etag = format!("\"{}\"", fixture.len());
+    let image_path = seed_part_file(&dest_dir, &fixture[..half], &etag);
+
+    let (events, mut events_rx) = EventSender::channel();
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client(),
+        &server.site(),
+        &DownloadConfig::default().events(events),
+    )
+    .expect("download should succeed");
+
+    assert_eq!(
+        server.range_requests(),
+        [(RESUMED_IMAGE.to_owned(), format!("bytes={}-", half))]
+    );
+    // Only the bytes received in this run are counted.
+    let mut received = None;
+    while let Ok(event) = events_rx.try_recv() {
+        if let Event::ImageFinished {
+            filename, bytes, ..
+        } = event
+        {
+            if filename == "pecorine_pc.png" {
+                received = Some(bytes);
+            }
+        }
+    }
+    assert_eq!(received, Some((fixture.len() - half) as u64));
+    assert_eq!(fs::read(&image_path).unwrap(), fixture);
+    assert_eq!(recorded_sha256(&image_path), sha256(&fixture));
+    assert!(!image_path.with_extension("png.part").exists());
+    assert!(!image_path.with_extension("png.part.validator").exists());
+    assert_eq!(manifest.items_to_repair(&dest_dir).count(), 0);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn changed_image_is_fetched_again() {
+    let server = MockServer::start();
+    let dest_dir = test_dir("resume-changed");
+    // The image has changed since the partial download.
+    let image_path = seed_part_file(&dest_dir, b"stale content", "\"0\"");
+    let fixture = resumed_fixture();
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client(),
+        &server.site(),
+        &DownloadConfig::default(),
+    )
+    .expect("download should succeed");
+
+    // Resumption is tried, but the server sends the whole new content.
+    assert_eq!(server.range_requests().len(), 1);
+    assert_eq!(fs::read(&image_path).unwrap(), fixture);
+    assert_eq!(recorded_sha256(&image_path), sha256(&fixture));
+    assert!(!image_path.with_extension("png.part").exists());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn unexpected_content_range_is_rejected() {
+    let server = MockServer::start();
+    server.break_ranges(RESUMED_IMAGE);
+    let dest_dir = test_dir("resume-mismatch");
+    let fixture = resumed_fixture();
+    let half = fixture.len() / 2;
+    let etag = format!("\"{}\"", fixture.len());
+    let image_path = seed_part_file(&dest_dir, &fixture[..half], &etag);
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client(),
+        &server.site(),
+        &DownloadConfig::default(),
+    )
+    .expect("download should succeed");
+
+    // The content is not appended to the partial content at a wrong offset.
+    assert!(!image_path.exists());
+    assert!(!image_path.with_extension("png.part").exists());
+    assert_eq!(
+        manifest.items_to_repair(&dest_dir).collect::<Vec<_>>(),
+        vec![FankitId::new(102)]
+    );
+
+    // The whole image is downloaded on the next run.
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client(),
+        &server.site(),
+        &DownloadConfig::default(),
+    )
+    .expect("download should succeed");
+    assert_eq!(server.range_requests().len(), 1);
+    assert_eq!(fs::read(&image_path).unwrap(), fixture);
+    assert_eq!(recorded_sha256(&image_path), sha256(&fixture));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn crawl_multiple_sections() {
+    let server = MockServer::start();
+    let client = client();
+    let sites = [server.section("fankit01"), server.section("fankit02")];
+    let dest_dir = test_dir("sections");
+
+    let mut manifest = Manifest::default();
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create a runtime");
+    runtime
+        .block_on(async {
+            let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+            let known = HashSet::new();
+            for site in &sites {
+                crawl_fankits_if_new_fankit_found(
+                    &known,
+                    &client,
+                    site,
+                    1,
+                    &fankits_tx,
+                    &EventSender::default(),
+                )
+                .await?;
+            }
+            drop(fankits_tx);
+            download_fankits_async(
+                &dest_dir,
+                fankits_rx,
+                &mut manifest,
+                &client,
+                &DownloadConfig::default().jobs(2),
+            )
+            .await
+        })
+        .expect("download should succeed");
+
+    assert_eq!(
+        manifest.items().collect::<HashSet<_>>(),
+        ids(&[50, 99, 100, 101, 102])
+    );
+    let section = |id| manifest.item(FankitId::new(id)).map(|item| item.section());
+    assert_eq!(section(50), Some("fankit01"));
+    assert_eq!(section(101), Some("fankit02"));
+    assert!(dest_dir.join("50-壁紙-ユイ/yui_pc.png").is_file());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn download_targets_without_crawl() {
+    let server = MockServer::start();
+    let client = client();
+    let sites = [server.section("fankit02"), server.section("fankit01")];
+    let dest_dir = test_dir("targets");
+
+    let targets = ["101..=102", &format!("{}/fankit01/50/", server.base_url())]
+        .iter()
+        .map(|s| FankitTarget::parse(s, &sites))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("targets should be parsed");
+    let mut manifest = Manifest::default();
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create a runtime");
+    runtime
+        .block_on(async {
+            let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+            for target in &targets {
+                for fankit in target.ids() {
+                    fankits_tx
+                        .send((target.site().clone(), fankit))
+                        .expect("receiver should be alive");
+                }
+            }
+            drop(fankits_tx);
+            download_fankits_async(
+                &dest_dir,
+                fankits_rx,
+                &mut manifest,
+                &client,
+                &DownloadConfig::default().jobs(2),
+            )
+            .await
+        })
+        .expect("download should succeed");
+
+    assert_eq!(
+        manifest.items().collect::<HashSet<_>>(),
+        ids(&[50, 101, 102])
+    );
+    assert!(dest_dir.join("50-壁紙-ユイ/yui_pc.png").is_file());
+    // No list pages are fetched.
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|path| path.ends_with("/fankit02/") || path.contains("/page/")));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn gaps_in_id_ranges_are_skipped() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("gaps");
+
+    let target = FankitTarget::parse("104..=108", std::slice::from_ref(&site))
+        .expect("target should be parsed");
+    let (events, mut events_rx) = EventSender::channel();
+    let config = DownloadConfig::default().jobs(2).events(events);
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &target.ids().collect(),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+    drop(config);
+
+    // No item pages exist for 107 and 108.
+    assert_eq!(
+        manifest.items().collect::<HashSet<_>>(),
+        ids(&[104, 105, 106])
+    );
+    let mut skipped = Vec::new();
+    while let Ok(event) = events_rx.try_recv() {
+        if let Event::ItemSkipped { id, reason } = event {
+            skipped.push((id.to_usize(), reason));
+        }
+    }
+    skipped.sort_by_key(|&(id, _)| id);
+    assert_eq!(
+        skipped,
+        vec![(107, SkipReason::NotFound), (108, SkipReason::NotFound)]
+    );
+    assert_eq!(
+        fs::read_dir(&dest_dir).unwrap().count(),
+        // Item directories and the manifest.
+        4
+    );
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn filtered_fankits_are_skipped() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("filter");
+
+    let filter = FankitFilter::default()
+        .include_type("壁紙")
+        .exclude_title(TitlePattern::glob("キャ*").unwrap())
+        .exclude_ids(FankitId::new(99)..=FankitId::new(99));
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[99, 100, 101, 102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().jobs(2).filter(filter),
+    )
+    .expect("download should succeed");
+
+    assert_eq!(manifest.items().collect::<HashSet<_>>(), ids(&[102]));
+    assert_eq!(
+        manifest.filtered().collect::<HashSet<_>>(),
+        ids(&[99, 100, 101])
+    );
+    let requests = server.requests();
+    // Excluded IDs are not loaded at all.
+    assert!(!requests.contains(&"/fankit02/99/".to_owned()));
+    // Images of the fankits rejected by the type or the title are not fetched.
+    assert!(requests.contains(&"/fankit02/100/".to_owned()));
+    assert!(requests.contains(&"/fankit02/101/".to_owned()));
+    assert!(!requests
+        .iter()
+        .any(|path| path.ends_with("karyl_pc.png") || path.ends_with("kokkoro_icon.png")));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn names_follow_templates() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("naming");
+
+    let config = DownloadConfig::default()
+        .dir_template(NameTemplate::item_dir("{id:05}-{type}/{title}").unwrap())
+        .file_template(NameTemplate::image_file("{id}-{filename}").unwrap());
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[101]),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+
+    let item = manifest
+        .item(FankitId::new(101))
+        .expect("should be recorded");
+    assert_eq!(item.dir(), "00101-アイコン/コッコロ");
+    assert!(dest_dir
+        .join("00101-アイコン/コッコロ/101-kokkoro_icon.png")
+        .is_file());
+    assert!(item.is_intact(&dest_dir));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn unsafe_titles_are_sanitized() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("sanitize");
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[104]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default(),
+    )
+    .expect("download should succeed");
+
+    // The title has a slash and is too long for a file name.
+    let item = manifest
+        .item(FankitId::new(104))
+        .expect("should be recorded");
+    assert!(item.dir().starts_with("104-アイコン-コッコロ_キャル"));
+    assert!(!item.dir().contains('/'));
+    assert!(item.dir().len() <= 200);
+    assert!(dest_dir.join(item.dir()).join("kokkoro_icon.png").is_file());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn colliding_filenames_are_disambiguated() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("collision");
+
+    let mut manifest = Manifest::default();
+    let config = DownloadConfig::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[105]),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+
+    let fixture = |name: &str| {
+        fs::read(fixtures_dir().join("wp-content/uploads/2020/02").join(name))
+            .expect("fixture should exist")
+    };
+    let item_dir = dest_dir.join("105-壁紙-ジュン");
+    let downloaded = |name: &str| fs::read(item_dir.join(name)).expect("should be downloaded");
+    assert_eq!(downloaded("01.png"), fixture("pc/01.png"));
+    assert_eq!(downloaded("01-2.png"), fixture("sp/01.png"));
+    // Percent-encoded names are decoded.
+    assert_eq!(downloaded("ジュン.png"), fixture("ジュン.png"));
+
+    // The names are recorded, and kept on repair.
+    let item = manifest
+        .item(FankitId::new(105))
+        .expect("should be recorded");
+    let sp = format!("{}/wp-content/uploads/2020/02/sp/01.png", server.base_url());
+    assert_eq!(
+        item.image(&sp).map(|image| image.filename()),
+        Some("01-2.png")
+    );
+    fs::remove_file(item_dir.join("01.png")).expect("failed to remove");
+    download_fankits(
+        &dest_dir,
+        &ids(&[105]),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+    assert_eq!(downloaded("01.png"), fixture("pc/01.png"));
+    assert!(!item_dir.join("01-3.png").exists());
+
+    // Parent segments can be kept instead.
+    let mut manifest = Manifest::default();
+    let dest_dir_segments = test_dir("collision-segments");
+    download_fankits(
+        &dest_dir_segments,
+        &ids(&[105]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().parent_segments(1),
+    )
+    .expect("download should succeed");
+    let item_dir = dest_dir_segments.join("105-壁紙-ジュン");
+    assert!(item_dir.join("pc_01.png").is_file());
+    assert!(item_dir.join("sp_01.png").is_file());
+    assert!(item_dir.join("02_ジュン.png").is_file());
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+    fs::remove_dir_all(&dest_dir_segments).expect("failed to clean up");
+}
+
+#[test]
+fn asset_kinds_are_selectable() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("assets");
+
+    let info = FankitId::new(106)
+        .load(&client, &site)
+        .expect("item should be loaded");
+    let kinds = info
+        .assets()
+        .iter()
+        .map(|asset| asset.kind())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            AssetKind::Image,
+            AssetKind::Image,
+            AssetKind::Image,
+            AssetKind::Archive,
+            AssetKind::Document
+        ]
+    );
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[106]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default().asset_kinds([AssetKind::Image, AssetKind::Document]),
+    )
+    .expect("download should succeed");
+
+    let item_dir = dest_dir.join("106-壁紙-ユキ");
+    for name in &[
+        "yuki_pc.JPEG",
+        "yuki_sp.webp",
+        "yuki_icon.gif",
+        "yuki_guide.pdf",
+    ] {
+        assert!(item_dir.join(name).is_file(), "{} should exist", name);
+    }
+    assert!(!item_dir.join("yuki_set.zip").exists());
+    assert!(!server
+        .requests()
+        .iter()
+        .any(|path| path.ends_with("yuki_set.zip")));
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn items_without_selected_assets_are_not_repaired() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("no-assets");
+
+    // Fankit 102 has no archives.
+    let config = DownloadConfig::default().asset_kinds([AssetKind::Archive]);
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+    assert_eq!(manifest.item(FankitId::new(102)).unwrap().images().len(), 0);
+    assert_eq!(manifest.items_to_repair(&dest_dir).count(), 0);
+
+    // Nothing is fetched on the next run.
+    let requests = server.requests().len();
+    let mut manifest =
+        Manifest::load(&dest_dir.join(MANIFEST_FILENAME)).expect("manifest should be saved");
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &config,
+    )
+    .expect("download should succeed");
+    assert_eq!(server.requests().len(), requests);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn images_keep_page_order_and_labels() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("labels");
+
+    let info = FankitId::new(102)
+        .load(&client, &site)
+        .expect("item should be loaded");
+    let assets = info
+        .assets()
+        .iter()
+        .map(|asset| {
+            (
+                asset.position(),
+                asset.label(),
+                asset.url().rsplit('/').next(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        assets,
+        [
+            (1, Some("スマートフォン"), Some("pecorine_sp.png")),
+            (2, Some("PC"), Some("pecorine_pc.png")),
+        ]
+    );
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default()
+            .file_template(NameTemplate::image_file("{position:02}-{label}.{ext}").unwrap()),
+    )
+    .expect("download should succeed");
+
+    let item_dir = dest_dir.join("102-壁紙-ペコリーヌ");
+    let fixture = |name: &str| {
+        fs::read(fixtures_dir().join("wp-content/uploads/2020/01").join(name))
+            .expect("fixture should exist")
+    };
+    let downloaded = |name: &str| fs::read(item_dir.join(name)).expect("should be downloaded");
+    assert_eq!(
+        downloaded("01-スマートフォン.png"),
+        fixture("pecorine_sp.png")
+    );
+    assert_eq!(downloaded("02-PC.png"), fixture("pecorine_pc.png"));
+
+    // The manifest and the sidecar list the images in the page order.
+    let item = manifest
+        .item(FankitId::new(102))
+        .expect("should be recorded");
+    let filenames = item
+        .images()
+        .iter()
+        .map(|image| image.filename())
+        .collect::<Vec<_>>();
+    assert_eq!(filenames, ["01-スマートフォン.png", "02-PC.png"]);
+    let sidecar: serde_json::Value = serde_json::from_slice(
+        &fs::read(item_dir.join(SIDECAR_FILENAME)).expect("sidecar should exist"),
+    )
+    .expect("sidecar should be valid");
+    assert_eq!(sidecar["images"][0]["position"], 1);
+    assert_eq!(sidecar["images"][0]["label"], "スマートフォン");
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("plan");
+
+    let mut manifest = Manifest::default();
+    download_fankits(
+        &dest_dir,
+        &ids(&[102]),
+        &mut manifest,
+        &client,
+        &site,
+        &DownloadConfig::default(),
+    )
+    .expect("download should succeed");
+    fs::remove_file(dest_dir.join("102-壁紙-ペコリーヌ/pecorine_sp.png"))
+        .expect("image should be downloaded");
+    let files_before = fs::read_dir(&dest_dir).unwrap().count();
+
+    let requests = server.requests().len();
+    let plans = plan_fankits(
+        &dest_dir,
+        &ids(&[101, 102]),
+        &manifest,
+        &client,
+        &site,
+        &DownloadConfig::default(),
+    )
+    .expect("plan should succeed");
+
+    assert_eq!(
+        plans
+            .iter()
+            .map(|plan| (plan.id(), plan.item_name(), plan.is_recorded()))
+            .collect::<Vec<_>>(),
+        vec![
+            (FankitId::new(101), "101-アイコン-コッコロ", false),
+            (FankitId::new(102), "102-壁紙-ペコリーヌ", true),
+        ]
+    );
+    assert_eq!(
+        plans[1]
+            .images()
+            .iter()
+            .map(|image| (image.filename(), image.exists()))
+            .collect::<Vec<_>>(),
+        // In the page order.
+        vec![("pecorine_sp.png", false), ("pecorine_pc.png", true)]
+    );
+    // Only the item pages are loaded, and nothing is written.
+    assert!(server.requests()[requests..]
+        .iter()
+        .all(|path| path.starts_with("/fankit02/")));
+    assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), files_before);
+    assert!(!dest_dir.join("101-アイコン-コッコロ").exists());
+    assert_eq!(
+        Manifest::load(&dest_dir.join(MANIFEST_FILENAME)).expect("manifest should be saved"),
+        manifest
+    );
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn events_are_reported() {
+    let server = MockServer::start();
+    let client = client();
+    let site = server.site();
+    let dest_dir = test_dir("events");
+
+    let (events, mut events_rx) = EventSender::channel();
+    let filter = FankitFilter::default().exclude_type("アイコン");
+    let config = DownloadConfig::default()
+        .jobs(2)
+        .filter(filter)
+        .events(events.clone());
+    let mut manifest = Manifest::default();
+    let runtime = tokio::runtime::Runtime::new().expect("failed to create a runtime");
+    runtime
+        .block_on(async {
+            let (fankits_tx, fankits_rx) = mpsc::unbounded_channel();
+            crawl_fankits_if_new_fankit_found(
+                &HashSet::new(),
+                &client,
+                &site,
+                1,
+                &fankits_tx,
+                &events,
+            )
+            .await?;
+            drop(fankits_tx);
+            download_fankits_async(&dest_dir, fankits_rx, &mut manifest, &client, &config).await
+        })
+        .expect("download should succeed");
+    drop((events, config));
+
+    let mut received = Vec::new();
+    while let Ok(event) = events_rx.try_recv() {
+        received.push(event);
+    }
+    let count = |name: &str| {
+        received
+            .iter()
+            .filter(|event| serde_json::to_value(event).unwrap()["event"] == name)
+            .count()
+    };
+    assert_eq!(count("list_page"), 3);
+    assert_eq!(count("fankit_found"), 4);
+    assert_eq!(count("item_parsed"), 4);
+    assert_eq!(count("item_done"), 3);
+    assert_eq!(count("image_started"), 4);
+    assert_eq!(count("image_finished"), 4);
+    assert!(received.contains(&Event::ItemSkipped {
+        id: FankitId::new(101),
+        reason: SkipReason::Filtered,
+    }));
+    // Image URLs are reported in the page order, matching the positions.
+    let pecorine_urls = received.iter().find_map(|event| match event {
+        Event::ItemParsed { id, image_urls, .. } if *id == FankitId::new(102) => {
+            Some(image_urls.clone())
+        }
+        _ => None,
+    });
+    assert_eq!(
+        pecorine_urls,
+        Some(vec![
+            format!(
+                "{}/wp-content/uploads/2020/01/pecorine_sp.png",
+                server.base_url()
+            ),
+            format!(
+                "{}/wp-content/uploads/2020/01/pecorine_pc.png",
+                server.base_url()
+            ),
+        ])
+    );
+    let karyl_size = fs::metadata(fixtures_dir().join("wp-content/uploads/2019/12/karyl_pc.png"))
+        .unwrap()
+        .len();
+    assert!(received.iter().any(|event| matches!(
+        event,
+        Event::ImageFinished { id, filename, bytes, .. }
+            if *id == FankitId::new(100) && filename == "karyl_pc.png" && *bytes == karyl_size
+    )));
+
+    let mut summary = Summary::default();
+    for event in &received {
+        summary.record(event);
+    }
+    let summary = serde_json::to_value(&summary).unwrap();
+    assert_eq!(summary["items_done"], 3);
+    assert_eq!(summary["items_skipped"], 1);
+    assert_eq!(summary["images_failed"], 0);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}
+
+#[test]
+fn run_downloads_and_reports() {
+    let server = MockServer::start();
+    let client = client();
+    let dest_dir = test_dir("run");
+
+    let config = RunConfig::new(&server.base_url(), [DEFAULT_SECTION])
+        .expect("config should be valid")
+        .dest_dir(dest_dir.clone())
+        .output(OutputFormat::Json)
+        .report(Some(PathBuf::from("report.json")))
+        .download(DownloadConfig::default().jobs(2));
+    let summary = run(&client, &config).expect("run should succeed");
+    assert_eq!(summary.items_done(), 4);
+    assert!(summary.is_success());
+    assert!(dest_dir.join(MANIFEST_FILENAME).is_file());
+    let report: serde_json::Value =
+        serde_json::from_slice(&fs::read(dest_dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(report["items_done"], 4);
+
+    // Nothing is fetched again once all fankits are downloaded.
+    let summary = run(&client, &config).expect("run should succeed");
+    assert_eq!(summary.items_done(), 0);
+    assert_eq!(summary.images_downloaded(), 0);
+
+    fs::remove_dir_all(&dest_dir).expect("failed to clean up");
+}